@@ -0,0 +1,79 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::Context;
+use lmml::midi::{MidiExportOptions, MidiImportOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileFormat {
+    /// LMML
+    Lmml,
+    /// Standard MIDI File
+    Mid,
+}
+
+impl FileFormat {
+    /// 拡張子からファイル形式を推測する
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "lmml" | "mml" | "txt" => Some(Self::Lmml),
+            "mid" | "midi" | "smf" => Some(Self::Mid),
+            _ => None,
+        }
+    }
+}
+
+/// `0=81`のような`@`の番号とGeneral MIDIのプログラム番号の組をパースする
+pub fn parse_program(s: &str) -> Result<(u32, u8), String> {
+    let (waveform, program) = s
+        .split_once('=')
+        .ok_or_else(|| format!("\"波形=プログラム番号\"の形式で指定してください: {}", s))?;
+    let waveform = waveform
+        .trim()
+        .parse()
+        .map_err(|e| format!("波形の番号が不正です: {}", e))?;
+    let program = program
+        .trim()
+        .parse()
+        .ok()
+        .filter(|p| *p <= 127)
+        .ok_or_else(|| format!("プログラム番号は0～127で指定してください: {}", program))?;
+    Ok((waveform, program))
+}
+
+pub fn convert(
+    file: &Path,
+    output: &Path,
+    from: FileFormat,
+    to: FileFormat,
+    programs: &[(u32, u8)],
+    quantize: u32,
+) -> anyhow::Result<()> {
+    match (from, to) {
+        (FileFormat::Lmml, FileFormat::Mid) => {
+            let timeline = crate::load_timeline(file)?;
+
+            let mut options = MidiExportOptions::default();
+            options.programs.extend(programs.iter().copied());
+
+            let out = File::create(output).with_context(|| {
+                format!("ファイル \"{}\"を作成できませんでした", output.display())
+            })?;
+            timeline
+                .write_smf(&options, BufWriter::new(out))
+                .with_context(|| format!("ファイル \"{}\"への書き込みエラー", output.display()))?;
+        }
+        (FileFormat::Mid, FileFormat::Lmml) => {
+            let input = std::fs::read(file)
+                .with_context(|| format!("ファイル \"{}\"を開けませんでした", file.display()))?;
+            let ast = lmml::midi::import_smf(&input, &MidiImportOptions { quantize })
+                .with_context(|| {
+                    format!("ファイル \"{}\"を変換できませんでした", file.display())
+                })?;
+            std::fs::write(output, format!("{}\n", ast))
+                .with_context(|| format!("ファイル \"{}\"への書き込みエラー", output.display()))?;
+        }
+        (from, to) => anyhow::bail!("{:?}から{:?}への変換には対応していません", from, to),
+    }
+    Ok(())
+}
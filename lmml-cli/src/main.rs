@@ -2,14 +2,24 @@
 #![deny(clippy::all)]
 #![deny(clippy::nursery)]
 
-use std::{io::Write, path::PathBuf};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use clap::Parser;
-use lmml::ast::{EvalEnv, LmmlAst};
-use nom::IResult;
+use lmml::{
+    ast::EvalEnv,
+    printer::{LetterCase, PrintOptions},
+    timeline::LmmlTimeline,
+};
+use lmml_parser::{FileResolver, LoadedLmml, SourceFile};
 use nom_language::error::VerboseError;
 
+mod convert;
+mod render;
+
 #[derive(Debug, Parser)]
 #[command(version, about)]
 pub struct Args {
@@ -23,26 +33,103 @@ pub enum SubCommand {
     Load { file: PathBuf },
     /// 対話的に演奏する
     Repl,
+    /// ファイルをWAV形式で書き出す
+    Render {
+        file: PathBuf,
+        /// 出力先のWAVファイル
+        #[arg(short, long)]
+        output: PathBuf,
+        /// サンプルのフォーマット
+        #[arg(long, value_enum, default_value_t = render::SampleFormat::F32)]
+        format: render::SampleFormat,
+    },
+    /// ファイルを他の形式に変換する
+    Convert {
+        file: PathBuf,
+        /// 出力先のファイル
+        #[arg(short, long)]
+        output: PathBuf,
+        /// 入力の形式 (省略時は拡張子から推測)
+        #[arg(long, value_enum)]
+        from: Option<convert::FileFormat>,
+        /// 出力の形式 (省略時は拡張子から推測)
+        #[arg(long, value_enum)]
+        to: Option<convert::FileFormat>,
+        /// `@`の番号に対応するGeneral MIDIのプログラム番号 (例: `--program 0=81`)
+        #[arg(long = "program", value_parser = convert::parse_program)]
+        programs: Vec<(u32, u8)>,
+        /// MIDIファイルを読み込むときに量子化の単位とする音符の長さ
+        #[arg(long, default_value_t = 32)]
+        quantize: u32,
+    },
+    /// ファイルを整形する
+    Fmt {
+        files: Vec<PathBuf>,
+        /// ファイルを書き換えず、整形が必要なファイルがあれば失敗する
+        #[arg(long)]
+        check: bool,
+        /// コマンドを大文字で書く
+        #[arg(long)]
+        upper: bool,
+        /// 1小節の長さ(四分音符の数)。0なら小節ごとに区切らない
+        #[arg(long, default_value_t = 4)]
+        bar: u32,
+        /// 行をチャンネルごとにまとめない
+        #[arg(long)]
+        no_group: bool,
+    },
 }
 
-fn unwrap_or_show_error(
-    ast: IResult<&str, LmmlAst, VerboseError<&str>>,
-    input: &str,
-) -> anyhow::Result<LmmlAst> {
-    match ast {
-        Err(err) => {
-            match err {
-                nom::Err::Incomplete(_) => {
-                    eprintln!("nom::Err::Incomplete");
-                }
-                nom::Err::Error(e) | nom::Err::Failure(e) => {
-                    eprintln!("{}", nom_language::error::convert_error(input, e));
-                }
-            }
-            anyhow::bail!("LMMLに構文エラーがあります")
+fn show_parse_error(err: nom::Err<VerboseError<&str>>, input: &str) -> anyhow::Error {
+    match err {
+        nom::Err::Incomplete(_) => {
+            eprintln!("nom::Err::Incomplete");
+        }
+        nom::Err::Error(e) | nom::Err::Failure(e) => {
+            eprintln!("{}", nom_language::error::convert_error(input, e));
         }
-        Ok((_, ast)) => Ok(ast),
     }
+    anyhow::anyhow!("LMMLに構文エラーがあります")
+}
+
+/// エラーの位置を示しながらASTを評価する
+fn eval_or_show_error(lmml: &LoadedLmml, env: &mut EvalEnv) -> anyhow::Result<LmmlTimeline> {
+    env.samples.extend(lmml.samples.clone());
+    lmml.ast.to_timeline(env).map_err(|err| {
+        let file = &lmml.files[err.span.file];
+        let line = file
+            .content
+            .lines()
+            .nth(err.span.line - 1)
+            .unwrap_or_default();
+        eprintln!(
+            "{} {}行目 {}列目: {}",
+            file.name, err.span.line, err.span.column, err.node
+        );
+        eprintln!("{}", line);
+        eprintln!("{}^", " ".repeat(err.span.column - 1));
+        anyhow::anyhow!("LMMLの評価中にエラーが発生しました")
+    })
+}
+
+/// ファイルを読み込み、`#include`を展開してパースする
+fn load_lmml_file(file: &Path) -> anyhow::Result<LoadedLmml> {
+    let main = FileResolver
+        .open(file)
+        .with_context(|| format!("ファイル \"{}\"を開けませんでした", file.display()))?;
+    Ok(lmml_parser::load_lmml(main, &mut FileResolver)?)
+}
+
+/// ファイルを読み込み、パースして評価する
+fn load_timeline(file: &Path) -> anyhow::Result<LmmlTimeline> {
+    let lmml = load_lmml_file(file)?;
+    eval_or_show_error(&lmml, &mut EvalEnv::default())
+}
+
+fn read_lmml_file(file: &Path) -> anyhow::Result<String> {
+    let input = std::fs::read_to_string(file)
+        .with_context(|| format!("ファイル \"{}\"を開けませんでした", file.display()))?;
+    Ok(input)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -50,22 +137,17 @@ fn main() -> anyhow::Result<()> {
 
     match args.subcommand {
         SubCommand::Load { file } => {
-            let input = std::fs::read_to_string(&file)
-                .with_context(|| format!("ファイル \"{}\"を開けませんでした", file.display()))?;
+            let lmml = load_lmml_file(&file)?;
             println!("lmml:");
-            println!("{}", input);
+            println!("{}", lmml.files[0].content);
             println!();
-            let input = lmml_parser::remove_comments(&input);
-            let ast = lmml_parser::parse_lmml(&input);
 
             println!("parser result:");
             println!("=== AST ===");
-            println!("{:#?}", ast);
+            println!("{:#?}", lmml.ast.to_ast());
             println!();
 
-            let ast = unwrap_or_show_error(ast, &input)?;
-
-            let timeline = ast.to_timeline(&mut EvalEnv::default());
+            let timeline = eval_or_show_error(&lmml, &mut EvalEnv::default())?;
             println!("=== Timeline ===");
             println!("{}", timeline);
 
@@ -98,20 +180,98 @@ fn main() -> anyhow::Result<()> {
                     continue;
                 }
 
-                let ast = lmml_parser::parse_lmml(line);
-                let ast = match unwrap_or_show_error(ast, line) {
+                let main = SourceFile {
+                    name: "<repl>".to_string(),
+                    id: "<repl>".to_string(),
+                    content: line.to_string(),
+                };
+                let lmml = match lmml_parser::load_lmml(main, &mut FileResolver) {
                     Err(e) => {
                         println!("{}", e);
                         continue;
                     }
-                    Ok(ast) => ast,
+                    Ok(lmml) => lmml,
                 };
-                let timeline = ast.to_timeline(&mut env);
+                // エラーが発生した場合は環境を元に戻す
+                let mut new_env = env.clone();
+                let timeline = match eval_or_show_error(&lmml, &mut new_env) {
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                    Ok(timeline) => timeline,
+                };
+                env = new_env;
                 println!("=== Timeline ===");
                 println!("{}", timeline);
                 timeline.play(&player);
             }
         }
+        SubCommand::Render {
+            file,
+            output,
+            format,
+        } => {
+            let timeline = load_timeline(&file)?;
+            render::render_to_wav(&timeline, &output, format)?;
+        }
+        SubCommand::Convert {
+            file,
+            output,
+            from,
+            to,
+            programs,
+            quantize,
+        } => {
+            let from = from
+                .or_else(|| convert::FileFormat::from_path(&file))
+                .with_context(|| "入力の形式を--fromで指定してください")?;
+            let to = to
+                .or_else(|| convert::FileFormat::from_path(&output))
+                .with_context(|| "出力の形式を--toで指定してください")?;
+            convert::convert(&file, &output, from, to, &programs, quantize)?;
+        }
+        SubCommand::Fmt {
+            files,
+            check,
+            upper,
+            bar,
+            no_group,
+        } => {
+            let options = PrintOptions {
+                case: if upper {
+                    LetterCase::Upper
+                } else {
+                    LetterCase::Lower
+                },
+                line_width: None,
+                group_by_channel: !no_group,
+                bar_length: (bar != 0).then_some(bar),
+            };
+            let mut unformatted = 0;
+            for file in files {
+                let input = read_lmml_file(&file)?;
+                let formatted = lmml_parser::format_lmml(&input, &options)
+                    .map_err(|e| show_parse_error(e, &input))
+                    .with_context(|| {
+                        format!("ファイル \"{}\"を整形できませんでした", file.display())
+                    })?;
+                if formatted == input {
+                    continue;
+                }
+                if check {
+                    println!("{}", file.display());
+                    unformatted += 1;
+                } else {
+                    std::fs::write(&file, formatted).with_context(|| {
+                        format!("ファイル \"{}\"への書き込みエラー", file.display())
+                    })?;
+                }
+            }
+            if unformatted != 0 {
+                anyhow::bail!("{}個のファイルが整形されていません", unformatted);
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use anyhow::Context;
+use lmml::{oscillator::SAMPLE_RATE, timeline::LmmlTimeline};
+use rodio::Source;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SampleFormat {
+    /// 32bit浮動小数点数
+    F32,
+    /// 16bit整数 (リニアPCM)
+    I16,
+}
+
+pub fn render_to_wav(
+    timeline: &LmmlTimeline,
+    path: &Path,
+    format: SampleFormat,
+) -> anyhow::Result<()> {
+    let music = timeline.to_music_wave();
+    let spec = hound::WavSpec {
+        channels: music.channels().get(),
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: match format {
+            SampleFormat::F32 => 32,
+            SampleFormat::I16 => 16,
+        },
+        sample_format: match format {
+            SampleFormat::F32 => hound::SampleFormat::Float,
+            SampleFormat::I16 => hound::SampleFormat::Int,
+        },
+    };
+
+    let mut writer = hound::WavWriter::create(path, spec)
+        .with_context(|| format!("ファイル \"{}\"を作成できませんでした", path.display()))?;
+    for sample in music {
+        match format {
+            SampleFormat::F32 => writer.write_sample(sample),
+            SampleFormat::I16 => {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            }
+        }
+        .with_context(|| format!("ファイル \"{}\"への書き込みエラー", path.display()))?;
+    }
+    writer
+        .finalize()
+        .with_context(|| format!("ファイル \"{}\"への書き込みエラー", path.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use lmml::{
+    ast::LmmlCommand,
+    printer::{PrintOptions, Printer, can_group_by_channel},
+};
+use nom::Offset;
+use nom_language::error::VerboseError;
+
+use crate::{parsers, remove_comments};
+
+/// コード行の前にある空行とコメント
+#[derive(Debug)]
+enum Prefix<'a> {
+    Blank,
+    Comment(&'a str),
+}
+
+/// 1つのチャンネルに対するコマンドが並んだ1行分のコード
+#[derive(Debug)]
+struct Segment<'a> {
+    prefix: Vec<Prefix<'a>>,
+    channel: u32,
+    commands: Vec<LmmlCommand>,
+    /// 複数行にまたがるループやマクロの定義がコメント行を含む場合の、書かれたままのコード
+    ///
+    /// コメントの位置を変えないよう、整形せずにそのまま出力する。
+    source: Option<&'a str>,
+}
+
+fn print_prefix(printer: &mut Printer<'_>, prefix: &[Prefix<'_>]) {
+    for p in prefix {
+        match p {
+            Prefix::Blank => printer.blank_line(),
+            Prefix::Comment(c) => printer.comment(c),
+        }
+    }
+}
+
+/// `copy`の一部を指すエラーを、`copy`と同じバイト位置を持つ`input`の一部を指すエラーに変換する
+fn rebase_error<'a>(
+    err: nom::Err<VerboseError<&str>>,
+    copy: &str,
+    input: &'a str,
+) -> nom::Err<VerboseError<&'a str>> {
+    err.map(|e| VerboseError {
+        errors: e
+            .errors
+            .into_iter()
+            .map(|(s, kind)| {
+                let offset = copy.offset(s);
+                (&input[offset..offset + s.len()], kind)
+            })
+            .collect(),
+    })
+}
+
+pub fn format_lmml<'a>(
+    input: &'a str,
+    options: &PrintOptions,
+) -> Result<String, nom::Err<VerboseError<&'a str>>> {
+    let mut segments: Vec<Segment<'a>> = Vec::new();
+    let mut prefix = Vec::new();
+    let mut channel = 0;
+    let mut has_channel = false;
+    // 書かれたまま出力するコードの中でチャンネルを変更しているかどうか
+    let mut has_verbatim_channel = false;
+
+    // ループやマクロの定義は複数行にまたがることがあるため、コメント行を空白に置き換えたものをパースする
+    let blanked = remove_comments(input);
+    let lines: Vec<(usize, &str)> = input
+        .split_inclusive('\n')
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len();
+            Some((start, line))
+        })
+        .collect();
+
+    let mut i = 0;
+    while i < lines.len() {
+        let (start, line) = lines[i];
+        i += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            if !segments.is_empty() || !prefix.is_empty() {
+                prefix.push(Prefix::Blank);
+            }
+            continue;
+        }
+        if trimmed.starts_with(';') {
+            prefix.push(Prefix::Comment(trimmed));
+            continue;
+        }
+
+        let mut end = start + line.len();
+        let mut first_err = None;
+        let mut has_comment = false;
+        let ast = loop {
+            match parsers::parse_lmml_until_eof(&blanked[start..end]) {
+                Ok((_, ast)) => break ast,
+                // 閉じていないループやマクロの定義があれば次の行と合わせてパースし直す
+                Err(e)
+                    if i < lines.len()
+                        && (blanked[start..end].contains("/:")
+                            || blanked[start..end].contains('=')) =>
+                {
+                    first_err.get_or_insert(e);
+                    let (_, next) = lines[i];
+                    i += 1;
+                    end += next.len();
+                    has_comment |= next.trim_start().starts_with(';');
+                }
+                Err(e) => return Err(rebase_error(first_err.unwrap_or(e), &blanked, input)),
+            }
+        };
+        if has_comment {
+            let segment_channel = channel;
+            for command in ast.0.iter() {
+                if let LmmlCommand::SetChannel(n) = command {
+                    has_channel = true;
+                    has_verbatim_channel = true;
+                    channel = *n;
+                }
+            }
+            segments.push(Segment {
+                prefix: std::mem::take(&mut prefix),
+                channel: segment_channel,
+                commands: ast.0,
+                source: Some(input[start..end].trim()),
+            });
+            continue;
+        }
+        let mut segment = Segment {
+            prefix: std::mem::take(&mut prefix),
+            channel,
+            commands: Vec::new(),
+            source: None,
+        };
+        for command in ast.0 {
+            if let LmmlCommand::SetChannel(n) = command {
+                has_channel = true;
+                channel = n;
+                if !segment.commands.is_empty() {
+                    segments.push(std::mem::replace(
+                        &mut segment,
+                        Segment {
+                            prefix: Vec::new(),
+                            channel,
+                            commands: Vec::new(),
+                            source: None,
+                        },
+                    ));
+                }
+                segment.channel = channel;
+            }
+            segment.commands.push(command);
+        }
+        segments.push(segment);
+    }
+
+    let group_by_channel = options.group_by_channel
+        && has_channel
+        && !has_verbatim_channel
+        && can_group_by_channel(segments.iter().flat_map(|s| &s.commands));
+    if group_by_channel {
+        // 最初に現れた順にチャンネルを並べ、各チャンネルの行は元の順序を保つ
+        let mut channels: Vec<u32> = Vec::new();
+        for segment in segments.iter() {
+            if !channels.contains(&segment.channel) {
+                channels.push(segment.channel);
+            }
+        }
+        segments.sort_by_key(|s| channels.iter().position(|c| *c == s.channel));
+    }
+
+    let mut printer = Printer::new(options);
+    let mut last_channel = None;
+    for segment in segments.iter() {
+        if group_by_channel && last_channel.is_some_and(|c| c != segment.channel) {
+            printer.blank_line();
+        }
+        last_channel = Some(segment.channel);
+
+        print_prefix(&mut printer, &segment.prefix);
+        if let Some(source) = segment.source {
+            for line in source.lines() {
+                printer.verbatim(line.trim_end());
+            }
+        } else {
+            for command in segment.commands.iter() {
+                printer.command(command);
+            }
+        }
+        printer.end_line();
+    }
+    print_prefix(&mut printer, &prefix);
+
+    let mut output = printer.finish();
+    output.push('\n');
+    Ok(output)
+}
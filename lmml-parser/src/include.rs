@@ -0,0 +1,310 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use lmml::{
+    ast::{CommandSpan, LmmlCommand, Span, SpannedCommand, SpannedLmmlAst},
+    oscillator::SampleData,
+};
+use nom::Offset;
+
+use crate::{parsers, remove_comments};
+
+/// LMMLのソースコードのファイル
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    /// エラーメッセージなどで使われるファイルの名前
+    pub name: String,
+    /// ファイルを一意に識別する文字列。`#include`の循環の検出に使われる。
+    pub id: String,
+    pub content: String,
+}
+
+/// `#include`で指定されたファイルを読み込む
+pub trait Resolver {
+    /// `from`に書かれた`#include "path"`が指すファイルを読み込む
+    fn resolve(&mut self, from: &SourceFile, path: &str) -> io::Result<SourceFile>;
+
+    /// `from`に書かれた`@s`コマンドが指す音声ファイルを読み込む
+    ///
+    /// ファイルを一意に識別する文字列と、ファイルの内容を返す。
+    fn resolve_binary(&mut self, from: &SourceFile, path: &str) -> io::Result<(String, Vec<u8>)> {
+        let _ = (from, path);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "音声ファイルの読み込みには対応していません",
+        ))
+    }
+}
+
+/// ファイルシステムからファイルを読み込む[`Resolver`]
+///
+/// 相対パスは`#include`が書かれたファイルのあるディレクトリを基準とする。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileResolver;
+
+impl FileResolver {
+    /// ファイルを読み込む
+    pub fn open(&self, path: impl AsRef<Path>) -> io::Result<SourceFile> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let id = std::fs::canonicalize(path)?;
+        Ok(SourceFile {
+            name: path.display().to_string(),
+            id: id.display().to_string(),
+            content,
+        })
+    }
+
+    /// `from`があるディレクトリを基準としたパス
+    fn join(from: &SourceFile, path: &str) -> PathBuf {
+        let base = Path::new(&from.name)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        base.join(path)
+    }
+}
+
+impl Resolver for FileResolver {
+    fn resolve(&mut self, from: &SourceFile, path: &str) -> io::Result<SourceFile> {
+        self.open(Self::join(from, path))
+    }
+
+    fn resolve_binary(&mut self, from: &SourceFile, path: &str) -> io::Result<(String, Vec<u8>)> {
+        let path = Self::join(from, path);
+        let content = std::fs::read(&path)?;
+        let id = std::fs::canonicalize(&path)?;
+        Ok((id.display().to_string(), content))
+    }
+}
+
+/// `#include`を展開したLMMLのプログラム
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedLmml {
+    /// 読み込んだファイル。[`Span::file`]はこの添字を表す。
+    pub files: Vec<SourceFile>,
+    pub ast: SpannedLmmlAst,
+    /// `@s`コマンドで読み込んだ音声ファイル
+    ///
+    /// `ast`の`@s`コマンドのパスはこのキーに書き換えられている。
+    /// 評価する前に[`lmml::ast::EvalEnv::samples`]に入れておく必要がある。
+    pub samples: BTreeMap<String, SampleData>,
+}
+
+/// `#include`の展開中に発生するエラー
+#[derive(Debug)]
+pub struct LoadError {
+    /// エラーが発生したファイルの名前
+    pub file: String,
+    /// エラーが発生した位置
+    pub span: Span,
+    pub kind: LoadErrorKind,
+}
+
+#[derive(Debug)]
+pub enum LoadErrorKind {
+    /// `#include`や`@s`で指定されたファイルを読み込めなかった
+    Io { path: String, error: io::Error },
+    /// `#include`が循環している
+    Cycle(String),
+    /// 構文エラー。`nom_language::error::convert_error`によるメッセージを持つ。
+    Syntax(String),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}行目 {}列目: ",
+            self.file, self.span.line, self.span.column
+        )?;
+        match &self.kind {
+            LoadErrorKind::Io { path, error } => {
+                write!(f, "ファイル\"{}\"を読み込めませんでした: {}", path, error)
+            }
+            LoadErrorKind::Cycle(path) => {
+                write!(f, "ファイル\"{}\"の読み込みが循環しています", path)
+            }
+            LoadErrorKind::Syntax(message) => write!(f, "構文エラーがあります\n{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+struct Loader<'r, R> {
+    resolver: &'r mut R,
+    files: Vec<SourceFile>,
+    /// 読み込み中のファイルの番号
+    stack: Vec<usize>,
+    samples: BTreeMap<String, SampleData>,
+}
+
+impl<R: Resolver> Loader<'_, R> {
+    fn error(&self, file: usize, span: Span, kind: LoadErrorKind) -> LoadError {
+        LoadError {
+            file: self.files[file].name.clone(),
+            span,
+            kind,
+        }
+    }
+
+    /// `file`番目のファイルに書かれた`#include "path"`を展開する
+    fn include(
+        &mut self,
+        file: usize,
+        span: Span,
+        path: &str,
+    ) -> Result<Vec<SpannedCommand>, LoadError> {
+        let source = self
+            .resolver
+            .resolve(&self.files[file], path)
+            .map_err(|error| {
+                let path = path.to_string();
+                self.error(file, span, LoadErrorKind::Io { path, error })
+            })?;
+        if self.stack.iter().any(|i| self.files[*i].id == source.id) {
+            return Err(self.error(file, span, LoadErrorKind::Cycle(path.to_string())));
+        }
+        self.files.push(source);
+        self.load(self.files.len() - 1)
+    }
+
+    /// `file`番目のファイルをパースし、`#include`を展開する
+    fn load(&mut self, file: usize) -> Result<Vec<SpannedCommand>, LoadError> {
+        let input = remove_comments(&self.files[file].content);
+        let ast = match parsers::parse_lmml_spanned_until_eof(&input) {
+            Ok((_, ast)) => ast,
+            Err(nom::Err::Incomplete(_)) => unreachable!("completeなパーサーのみを使用している"),
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                let offset = e.errors.first().map_or(0, |(s, _)| input.offset(s));
+                let mut span = Span::locate(&input, offset);
+                span.file = file;
+                let message = nom_language::error::convert_error(input.as_str(), e);
+                return Err(self.error(file, span, LoadErrorKind::Syntax(message)));
+            }
+        };
+
+        self.stack.push(file);
+        let mut commands = Vec::with_capacity(ast.0.len());
+        for SpannedCommand {
+            mut span,
+            node,
+            mut body,
+        } in ast.0
+        {
+            span.file = file;
+            set_file(&mut body, file);
+            match node {
+                LmmlCommand::Include(path) => commands.extend(self.include(file, span, &path)?),
+                node => {
+                    let (node, body) = self.expand(file, span, node, body)?;
+                    commands.push(SpannedCommand { span, node, body });
+                }
+            }
+        }
+        self.stack.pop();
+        Ok(commands)
+    }
+
+    /// `@s`コマンドの音声ファイルを読み込み、パスをファイルを識別する文字列に書き換える
+    fn sample(&mut self, file: usize, span: Span, path: String) -> Result<String, LoadError> {
+        let io_error = |loader: &Self, error| {
+            let path = path.clone();
+            loader.error(file, span, LoadErrorKind::Io { path, error })
+        };
+        let (id, content) = self
+            .resolver
+            .resolve_binary(&self.files[file], &path)
+            .map_err(|e| io_error(self, e))?;
+        if !self.samples.contains_key(&id) {
+            let data = SampleData::from_wav(content.as_slice()).map_err(|e| io_error(self, e))?;
+            self.samples.insert(id.clone(), data);
+        }
+        Ok(id)
+    }
+
+    /// ループやマクロの中の`#include`を展開し、`@s`コマンドの音声ファイルを読み込む
+    ///
+    /// `spans`はループやマクロの本体の各コマンドの位置で、展開後の本体の位置を合わせて返す。
+    fn expand(
+        &mut self,
+        file: usize,
+        span: Span,
+        command: LmmlCommand,
+        spans: Vec<CommandSpan>,
+    ) -> Result<(LmmlCommand, Vec<CommandSpan>), LoadError> {
+        let mut result_spans = Vec::with_capacity(spans.len());
+        let body = |loader: &mut Self, body: Vec<LmmlCommand>| {
+            let mut result = Vec::with_capacity(body.len());
+            for (command, span) in body.into_iter().zip(spans) {
+                match command {
+                    LmmlCommand::Include(path) => {
+                        for command in loader.include(file, span.span, &path)? {
+                            result_spans.push(command.to_command_span());
+                            result.push(command.node);
+                        }
+                    }
+                    command => {
+                        let (command, body) = loader.expand(file, span.span, command, span.body)?;
+                        result_spans.push(CommandSpan {
+                            span: span.span,
+                            body,
+                        });
+                        result.push(command);
+                    }
+                }
+            }
+            Ok(result)
+        };
+        let command = match command {
+            LmmlCommand::Loop { body: b, count } => LmmlCommand::Loop {
+                body: body(self, b)?,
+                count,
+            },
+            LmmlCommand::DefineMacro { name, body: b } => LmmlCommand::DefineMacro {
+                name,
+                body: body(self, b)?,
+            },
+            LmmlCommand::DefineSample {
+                number,
+                path,
+                root,
+                loop_frames,
+            } => LmmlCommand::DefineSample {
+                number,
+                path: self.sample(file, span, path)?,
+                root,
+                loop_frames,
+            },
+            command => command,
+        };
+        Ok((command, result_spans))
+    }
+}
+
+/// ループやマクロの本体の各コマンドの位置に、ファイルの番号を設定する
+fn set_file(spans: &mut [CommandSpan], file: usize) {
+    for span in spans {
+        span.span.file = file;
+        set_file(&mut span.body, file);
+    }
+}
+
+pub fn load_lmml(main: SourceFile, resolver: &mut impl Resolver) -> Result<LoadedLmml, LoadError> {
+    let mut loader = Loader {
+        resolver,
+        files: vec![main],
+        stack: Vec::new(),
+        samples: BTreeMap::new(),
+    };
+    let commands = loader.load(0)?;
+    Ok(LoadedLmml {
+        files: loader.files,
+        ast: SpannedLmmlAst(commands),
+        samples: loader.samples,
+    })
+}
@@ -2,24 +2,558 @@
 #![deny(clippy::all)]
 #![deny(clippy::nursery)]
 
-use lmml::ast::LmmlAst;
+use lmml::{
+    ast::{LmmlAst, SpannedLmmlAst},
+    printer::PrintOptions,
+};
 use nom::IResult;
 use nom_language::error::VerboseError;
 
+mod format;
+mod include;
 mod parsers;
 
+pub use include::{FileResolver, LoadError, LoadErrorKind, LoadedLmml, Resolver, SourceFile};
+
+/// `;`から始まる行を取り除く
+///
+/// 取り除いた行は同じバイト数の空白で置き換えられるため、
+/// 結果をパースして得られる位置は元の文字列での位置と一致する。
 pub fn remove_comments(input: &str) -> String {
-    let mut v = Vec::new();
-    for line in input.lines() {
-        if line.trim_start().starts_with(';') {
-            v.push("");
-            continue;
+    let mut result = String::with_capacity(input.len());
+    for line in input.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        if content.trim_start().starts_with(';') {
+            result.extend(std::iter::repeat_n(' ', content.len()));
+            result.push_str(&line[content.len()..]);
+        } else {
+            result.push_str(line);
         }
-        v.push(line);
     }
-    v.join("\n")
+    result
 }
 
 pub fn parse_lmml(input: &str) -> IResult<&str, LmmlAst, VerboseError<&str>> {
     parsers::parse_lmml_until_eof(input)
 }
+
+/// 各コマンドにソースコード上の位置を付けてパースする
+pub fn parse_lmml_spanned(input: &str) -> IResult<&str, SpannedLmmlAst, VerboseError<&str>> {
+    parsers::parse_lmml_spanned_until_eof(input)
+}
+
+/// `main`をパースし、`#include`で指定されたファイルを`resolver`で読み込んで展開する
+///
+/// コメントは取り除かれる。ループやマクロの中で読み込まれたコマンドにも、
+/// 読み込まれたファイルでの位置が付けられる。
+pub fn load_lmml(main: SourceFile, resolver: &mut impl Resolver) -> Result<LoadedLmml, LoadError> {
+    include::load_lmml(main, resolver)
+}
+
+/// LMMLのソースコードを整形する
+///
+/// 行の区切りと`;`から始まるコメント行は保たれる。
+/// コメント行を含む複数行のループやマクロの定義は、整形せずに書かれたまま出力する。
+/// `options.group_by_channel`が`true`なら、行をチャンネルごとにまとめて並べ替える。
+pub fn format_lmml<'a>(
+    input: &'a str,
+    options: &PrintOptions,
+) -> Result<String, nom::Err<VerboseError<&'a str>>> {
+    format::format_lmml(input, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use lmml::printer::{LetterCase, PrintOptions};
+
+    use super::*;
+
+    const SOURCE: &str = "t80 l8. c+ d-4 e8. r8 R [ga+df]2 n60 o3 >c< c4&c8^ e^16. /: c /:d:/ / e :/3 /:f:/ !A = c d; !a_2=!A e; !a_2 q6 @e1 = { 10, 20,50 ,100} @E1 @e0 @w10={0, 3,7,15} @10 p0 P127 @d{250, 40,30} @R{50,50,20} @c{ 5,3,50 } @L{1,800,30} @m{1, 24} @v{30,55,200} @g40 c~e @G0 @s12={\"kick.wav\",36} @S13={\"pad.wav\",60,10,20} @f11={4,3,1,50,0,100,80,10,2,100,5,200,50,100,0,40,0,0,100,0,1,80,0,0,100,0} @11 v15 @4 :1 @3 v25 b-16 :15 >>>l2.";
+
+    fn parse(input: &str) -> LmmlAst {
+        let (rest, ast) = parse_lmml(input).unwrap();
+        assert!(rest.is_empty());
+        ast
+    }
+
+    #[test]
+    fn print_roundtrip() {
+        let ast = parse(SOURCE);
+        for case in [LetterCase::Lower, LetterCase::Upper] {
+            for line_width in [None, Some(1), Some(16), Some(80)] {
+                for bar_length in [None, Some(3), Some(4)] {
+                    let options = PrintOptions {
+                        case,
+                        line_width,
+                        group_by_channel: false,
+                        bar_length,
+                    };
+                    let printed = ast.to_lmml(&options);
+                    assert_eq!(parse(&printed), ast, "{}", printed);
+                    // 幅を超えてよいのは1つのコマンドだけの行
+                    if let Some(width) = line_width {
+                        assert!(
+                            printed
+                                .lines()
+                                .all(|l| l.len() <= width.max(8) || !l.contains(' '))
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn print_grouped_by_channel() {
+        let ast = parse("c :1 d :0 e :1 f");
+        let options = PrintOptions {
+            group_by_channel: true,
+            ..Default::default()
+        };
+        assert_eq!(ast.to_lmml(&options), ":0 c e\n\n:1 d f");
+    }
+
+    #[test]
+    fn parse_spanned() {
+        let input = remove_comments("; コメント\r\nt80\r\n  c+ [ceg]4\n");
+        let (_, ast) = parse_lmml_spanned(&input).unwrap();
+        let spans: Vec<_> = ast.0.iter().map(|c| c.span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                lmml::ast::Span::locate(&input, 16),
+                lmml::ast::Span::locate(&input, 23),
+                lmml::ast::Span::locate(&input, 26),
+            ]
+        );
+        assert_eq!(
+            spans.iter().map(|s| (s.line, s.column)).collect::<Vec<_>>(),
+            vec![(2, 1), (3, 3), (3, 6)]
+        );
+        assert_eq!(LmmlAst::from(ast), parse(&input));
+    }
+
+    #[test]
+    fn eval_error_span() {
+        use lmml::ast::{EvalEnv, EvalError};
+
+        let error_at = |input: &str| {
+            let (_, ast) = parse_lmml_spanned(input).unwrap();
+            let error = ast.to_timeline(&mut EvalEnv::default()).unwrap_err();
+            (error.span.line, error.span.column, error.node)
+        };
+        // ループやマクロの本体のエラーは、本体の中のコマンドの位置を指す
+        assert_eq!(
+            error_at("l4 cdef\n/: c d\n e :16 f :/2"),
+            (3, 4, EvalError::ChannelOutOfRange(16))
+        );
+        assert_eq!(
+            error_at("/: c /: d\n  :16 :/ :/"),
+            (2, 3, EvalError::ChannelOutOfRange(16))
+        );
+        assert_eq!(
+            error_at("!A = c\n  t0;\nl8 !A"),
+            (2, 3, EvalError::ZeroTempo)
+        );
+        assert_eq!(
+            error_at("c\n!B"),
+            (2, 1, EvalError::UndefinedMacro("B".into()))
+        );
+        // 後に音が続かない`&`や`~`は、その位置を指す
+        assert_eq!(
+            error_at("c d\n/: e & :/1 :1 f"),
+            (2, 6, EvalError::NothingAfterTie)
+        );
+        assert_eq!(error_at("c ~\n:1 c"), (1, 3, EvalError::NothingAfterSlide));
+    }
+
+    #[test]
+    fn number_overflow() {
+        assert_eq!(parse("c4294967295").0.len(), 1);
+        for input in ["c99999999999", "t4294967296", "[ceg]99999999999."] {
+            let Err(nom::Err::Failure(e)) = parse_lmml(input) else {
+                panic!("{}", input)
+            };
+            assert!(
+                e.errors.iter().any(|(_, kind)| matches!(
+                    kind,
+                    nom_language::error::VerboseErrorKind::Context(c) if c.contains("数値が大きすぎます")
+                )),
+                "{:?}",
+                e
+            );
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parse_never_panics(input in "\\PC*") {
+            let _ = parse_lmml(&input);
+            let _ = parse_lmml_spanned(&input);
+            let _ = format_lmml(&input, &PrintOptions::default());
+        }
+
+        #[test]
+        fn parse_lmml_like_never_panics(input in "[a-gA-GrRnNoOlLvVtT@:<>+\\-.\\[\\] ;\n0-9]{0,64}") {
+            let parsed = parse_lmml(&input);
+            let spanned = parse_lmml_spanned(&input);
+            let _ = format_lmml(&input, &PrintOptions::default());
+            if let (Ok((_, ast)), Ok((_, spanned))) = (parsed, spanned) {
+                proptest::prop_assert_eq!(ast, LmmlAst::from(spanned));
+            }
+        }
+    }
+
+    #[test]
+    fn format() {
+        let input =
+            "; title\n\n:0 T120 L8 CDEF GABC :1 l2 c\n\n\n  ; melody\n:0 >c4.<b8 a2\n:1 d\n; end\n";
+        let options = PrintOptions {
+            group_by_channel: true,
+            bar_length: Some(4),
+            ..Default::default()
+        };
+        let expected =
+            "; title\n\n:0 t120 l8 cdefgabc\n\n; melody\n:0 >c4.<b8a2\n\n:1 l2 c\n:1 d\n; end\n";
+        let formatted = format_lmml(input, &options).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format_lmml(&formatted, &options).unwrap(), expected);
+    }
+
+    #[test]
+    fn format_multiline_loop() {
+        // コメントを含まなければ1行にまとめる
+        assert_eq!(
+            format_lmml("c /: d\n  e / f\n:/3 g\nr\n", &PrintOptions::default()).unwrap(),
+            "c /: d e / f :/3 g\nr\n"
+        );
+        // コメントを含むループやマクロの定義は書かれたまま保つ
+        for input in [
+            "c /: d\n; inner\n  e / f\n:/3 g\nr\n",
+            ":1 o3 /: c d\n; inside loop\ne f :/2\n",
+            "!A = c\n; inside macro\nd;\n!A\n",
+        ] {
+            assert_eq!(format_lmml(input, &PrintOptions::default()).unwrap(), input);
+        }
+        let options = PrintOptions {
+            group_by_channel: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_lmml(":1 c\n:0 d\n/: e\n; x\nf :/\n:1 g\n", &options).unwrap(),
+            ":1 c\n:1 g\n\n:0 d\n/: e\n; x\nf :/\n"
+        );
+        // 書かれたままのコードの中でチャンネルを変更している場合はまとめない
+        assert_eq!(
+            format_lmml(":1 c\n:0 /: d\n; x\ne :/\n:1 f\n", &options).unwrap(),
+            ":1 c\n:0 /: d\n; x\ne :/\n:1 f\n"
+        );
+
+        let Err(nom::Err::Error(e)) = format_lmml("c\n/: d\ne\n", &PrintOptions::default()) else {
+            panic!()
+        };
+        assert_eq!(e.errors[0].0, "/: d\n");
+    }
+
+    #[test]
+    fn group_with_macros() {
+        let options = PrintOptions {
+            group_by_channel: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("!A = c; :1 !A :0 d :1 e").to_lmml(&options),
+            ":0 !A = c; d\n\n:1 !A e"
+        );
+        assert_eq!(
+            parse(":1 c !A = d; :0 !A :1 e").to_lmml(&options),
+            ":1 c !A = d; e\n\n:0 !A"
+        );
+        assert_eq!(
+            parse(":1 c :0 !A = d; :1 !A").to_lmml(&options),
+            ":1 c\n:0 !A = d;\n:1 !A"
+        );
+        assert_eq!(
+            parse("!A = :1 c; !A :0 d").to_lmml(&options),
+            "!A =\n:1 c; !A\n:0 d"
+        );
+        assert_eq!(
+            parse(":1 c :0 @w10={1,2} :1 @10").to_lmml(&options),
+            ":1 c\n:0 @w10={1,2}\n:1 @10"
+        );
+        // 定義し直す場合や、他のチャンネルのコマンドより後で定義する場合はまとめない
+        assert_eq!(
+            parse(":0 !A = c; :1 !A :0 !A = d;").to_lmml(&options),
+            ":0 !A = c;\n:1 !A\n:0 !A = d;"
+        );
+    }
+
+    #[test]
+    fn group_keeps_timeline() {
+        use lmml::ast::EvalEnv;
+
+        let options = PrintOptions {
+            group_by_channel: true,
+            ..Default::default()
+        };
+        for source in [
+            "!A = c; :1 !A :0 d :1 e",
+            ":1 c !A = d; :0 !A :1 e",
+            ":0 !A = c; :1 !A :0 !A = d;",
+            ":0 !A = c; !A = d; :1 !A",
+            ":0 c :1 d :0 @e1={0,0,50,0} :1 @e1 e",
+            ":0 @w10={0,1} @w10={1,0} :1 @10 c",
+            ":0 /: @e1={0,0,50,0} :/ :1 @e1 c",
+            ":0 !A = @e1={0,0,50,0}; :1 c :0 !A :1 @e1 c",
+            ":0 !A = @e1={0,0,50,0}; !A :1 @e1 c :0 !A",
+            ":0 c d :1 e :2 f :0 g",
+        ] {
+            let ast = parse(source);
+            let grouped = parse(&ast.to_lmml(&options));
+            assert_eq!(
+                grouped.to_timeline(&mut EvalEnv::default()),
+                ast.to_timeline(&mut EvalEnv::default()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    /// ファイル名と内容の組からファイルを読み込む
+    struct MemoryResolver(Vec<(&'static str, &'static str)>);
+
+    impl MemoryResolver {
+        fn source(&self, name: &str) -> Option<SourceFile> {
+            self.0
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(name, content)| SourceFile {
+                    name: name.to_string(),
+                    id: name.to_string(),
+                    content: content.to_string(),
+                })
+        }
+    }
+
+    impl Resolver for MemoryResolver {
+        fn resolve(&mut self, _from: &SourceFile, path: &str) -> std::io::Result<SourceFile> {
+            self.source(path)
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        }
+    }
+
+    /// 音声ファイルを読み込めるようにした[`MemoryResolver`]
+    struct SampleResolver(Vec<(&'static str, Vec<f32>)>);
+
+    impl Resolver for SampleResolver {
+        fn resolve(&mut self, _from: &SourceFile, _path: &str) -> std::io::Result<SourceFile> {
+            Err(std::io::ErrorKind::NotFound.into())
+        }
+
+        fn resolve_binary(
+            &mut self,
+            _from: &SourceFile,
+            path: &str,
+        ) -> std::io::Result<(String, Vec<u8>)> {
+            let (_, samples) = self
+                .0
+                .iter()
+                .find(|(name, _)| *name == path)
+                .ok_or(std::io::ErrorKind::NotFound)?;
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: 22050,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            let mut wav = std::io::Cursor::new(Vec::new());
+            let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+            for sample in samples {
+                writer.write_sample(*sample).unwrap();
+            }
+            writer.finalize().unwrap();
+            Ok((format!("/{}", path), wav.into_inner()))
+        }
+    }
+
+    #[test]
+    fn sample() {
+        use lmml::{
+            ast::{EvalEnv, EvalError, LmmlCommand},
+            timeline::{Element, Instrument, NoteType},
+        };
+
+        let mut resolver = SampleResolver(vec![("a.wav", vec![0.0, 0.5, 1.0, 0.5])]);
+        let mut load = |content: &str| {
+            let main = SourceFile {
+                name: "main".to_string(),
+                id: "main".to_string(),
+                content: content.to_string(),
+            };
+            load_lmml(main, &mut resolver)
+        };
+        let eval = |lmml: &LoadedLmml| {
+            let mut env = EvalEnv {
+                samples: lmml.samples.clone(),
+                ..Default::default()
+            };
+            lmml.ast.to_timeline(&mut env).map_err(|e| e.node)?;
+            Ok(env)
+        };
+
+        let lmml = load("@s10={\"a.wav\",69,1,3} /: @s11={\"a.wav\"} :/ @10 c").unwrap();
+        assert_eq!(lmml.samples.len(), 1);
+        assert_eq!(lmml.samples["/a.wav"].sample_rate, 22050);
+        assert_eq!(*lmml.samples["/a.wav"].frames, [0.0, 0.5, 1.0, 0.5]);
+        assert_eq!(
+            lmml.ast.0[0].node,
+            LmmlCommand::DefineSample {
+                number: 10,
+                path: "/a.wav".to_string(),
+                root: 69,
+                loop_frames: Some((1, 3)),
+            }
+        );
+        let env = eval(&lmml).unwrap();
+        assert!(matches!(
+            env.instruments.get(&10).map(|i| &**i),
+            Some(Instrument::Sample(sample)) if sample.root == 69
+        ));
+        assert!(matches!(
+            env.instruments.get(&11).map(|i| &**i),
+            Some(Instrument::Sample(sample)) if sample.root == 60 && sample.loop_frames.is_none()
+        ));
+
+        // 定義し直す前に置いた音は元の音色のまま
+        let lmml = load("@s10={\"a.wav\",69} @10 c @s10={\"a.wav\",60} c").unwrap();
+        let mut env = EvalEnv {
+            samples: lmml.samples.clone(),
+            ..Default::default()
+        };
+        let timeline = lmml.ast.to_timeline(&mut env).unwrap();
+        let roots: Vec<_> = timeline.timeline[0]
+            .iter()
+            .filter_map(|element| match element {
+                Element::Note(note) => match note.note_type {
+                    NoteType::Single { ref instrument, .. } => match instrument.as_deref() {
+                        Some(Instrument::Sample(sample)) => Some(sample.root),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                Element::Event(_) => None,
+            })
+            .collect();
+        assert_eq!(roots, vec![69, 60]);
+
+        assert!(matches!(
+            load("c @s10={\"none.wav\"}"),
+            Err(LoadError {
+                kind: LoadErrorKind::Io { .. },
+                ..
+            })
+        ));
+        assert_eq!(
+            eval(&load("@s10={\"a.wav\",60,2,5}").unwrap()).err(),
+            Some(EvalError::SampleLoopOutOfRange {
+                start: 2,
+                end: 5,
+                frames: 4
+            })
+        );
+        assert_eq!(
+            parse("@s10={\"a.wav\"}").to_timeline(&mut EvalEnv::default()),
+            Err(EvalError::UnresolvedSample("a.wav".to_string()))
+        );
+        assert!(matches!(
+            parse_lmml("@s10={\"a.wav\",60,1}"),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
+    #[test]
+    fn include() {
+        use lmml::ast::{EvalEnv, EvalError};
+
+        let mut resolver = MemoryResolver(vec![
+            (
+                "main",
+                "c #include \"part\" /: #include \"drum\" :/\n!A = #include \"drum\";",
+            ),
+            ("part", "; part\nd\n#include \"drum\"\n"),
+            ("drum", "e"),
+            ("cycle", "c\n  #include \"cycle2\""),
+            ("cycle2", "#include \"cycle\""),
+            ("missing", "c #include \"none\""),
+            ("syntax", "#include \"bad\""),
+            ("bad", "c\nd x"),
+            ("eval", "#include \"eval2\""),
+            ("eval2", "\n  t0"),
+        ]);
+        let mut load = |name| {
+            let main = resolver.source(name).unwrap();
+            load_lmml(main, &mut resolver)
+        };
+
+        let lmml = load("main").unwrap();
+        assert_eq!(
+            LmmlAst::from(lmml.ast.clone()),
+            parse("c d e /: e :/ !A = e;")
+        );
+        let names: Vec<_> = lmml.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "part", "drum", "drum", "drum"]);
+        let spans: Vec<_> = lmml
+            .ast
+            .0
+            .iter()
+            .map(|c| (c.span.file, c.span.line))
+            .collect();
+        assert_eq!(spans, vec![(0, 1), (1, 2), (2, 1), (0, 1), (0, 2)]);
+        // ループやマクロの中で読み込まれたコマンドは、読み込まれたファイルでの位置を持つ
+        let body: Vec<_> = lmml.ast.0[3..]
+            .iter()
+            .map(|c| (c.body[0].span.file, c.body[0].span.line))
+            .collect();
+        assert_eq!(body, vec![(3, 1), (4, 1)]);
+
+        let err = load("cycle").unwrap_err();
+        assert!(matches!(&err.kind, LoadErrorKind::Cycle(path) if path == "cycle"));
+        assert_eq!(
+            (err.file.as_str(), err.span.line, err.span.column),
+            ("cycle2", 1, 1)
+        );
+
+        let err = load("missing").unwrap_err();
+        assert!(matches!(&err.kind, LoadErrorKind::Io { path, .. } if path == "none"));
+        assert_eq!(
+            (err.file.as_str(), err.span.line, err.span.column),
+            ("missing", 1, 3)
+        );
+
+        let err = load("syntax").unwrap_err();
+        assert!(matches!(err.kind, LoadErrorKind::Syntax(_)));
+        assert_eq!(
+            (err.file.as_str(), err.span.line, err.span.column),
+            ("bad", 2, 3)
+        );
+
+        let lmml = load("eval").unwrap();
+        let err = lmml.ast.to_timeline(&mut EvalEnv::default()).unwrap_err();
+        assert_eq!(err.node, EvalError::ZeroTempo);
+        assert_eq!(lmml.files[err.span.file].name, "eval2");
+        assert_eq!((err.span.line, err.span.column), (2, 3));
+
+        assert_eq!(
+            parse("#include \"a b.lmml\"").to_timeline(&mut EvalEnv::default()),
+            Err(EvalError::UnresolvedInclude("a b.lmml".to_string()))
+        );
+        assert_eq!(
+            parse(":1 c #include \"x\"").to_lmml(&PrintOptions {
+                case: LetterCase::Upper,
+                group_by_channel: true,
+                ..Default::default()
+            }),
+            ":1 C #include \"x\""
+        );
+    }
+}
@@ -1,15 +1,27 @@
-use lmml::ast::{LmmlAst, LmmlCommand, NoteChar, NoteModifier};
+use lmml::{
+    ast::{
+        CommandSpan, LmmlAst, LmmlCommand, NoteChar, NoteModifier, Span, SpannedCommand,
+        SpannedLmmlAst,
+    },
+    effect::{Chorus, Delay, Reverb},
+    filter::{Filter, FilterKind},
+    oscillator::Vibrato,
+};
 use nom::{
-    IResult, Parser,
+    IResult, Offset, Parser,
     branch::alt,
-    character::complete::{char, multispace0, none_of, one_of},
-    combinator::{eof, map, opt, value},
-    error::ParseError,
-    multi::{many0, many1},
+    bytes::complete::{tag, take_while},
+    character::complete::{char, digit1, multispace0, none_of, one_of, satisfy},
+    combinator::{cut, eof, map, map_opt, map_res, not, opt, peek, recognize, value},
+    error::{ParseError, context},
+    multi::{many0, many1, separated_list1},
     sequence::{delimited, pair, preceded, terminated},
 };
 use nom_language::error::VerboseError;
 
+/// `@s`コマンドで元の音の高さを省略した場合のノート番号(`o4 c`)
+const DEFAULT_SAMPLE_ROOT: u32 = 60;
+
 pub fn parse_lmml_until_eof(input: &str) -> IResult<&str, LmmlAst, VerboseError<&str>> {
     terminated(parse_lmml, eof).parse(input)
 }
@@ -26,20 +38,127 @@ pub fn parse_lmml(input: &str) -> IResult<&str, LmmlAst, VerboseError<&str>> {
     .parse(input)
 }
 
+pub fn parse_lmml_spanned_until_eof(
+    input: &str,
+) -> IResult<&str, SpannedLmmlAst, VerboseError<&str>> {
+    let (rest, mut commands) = terminated(
+        many0(delimited(
+            alt((value((), multispace0), value((), opt(comment)))),
+            |i| parse_spanned_command(input, i),
+            alt((value((), multispace0), value((), opt(comment)))),
+        )),
+        eof,
+    )
+    .parse(input)?;
+
+    // 位置はバイト数だけが入っているので、行番号と列番号をまとめて求める
+    fn offsets(span: &CommandSpan, result: &mut Vec<usize>) {
+        result.push(span.span.offset);
+        for span in &span.body {
+            offsets(span, result);
+        }
+    }
+    fn locate(span: &mut CommandSpan, located: &mut impl Iterator<Item = Span>) {
+        span.span = located.next().unwrap();
+        for span in &mut span.body {
+            locate(span, located);
+        }
+    }
+    let mut all = Vec::new();
+    for (_, span) in &commands {
+        offsets(span, &mut all);
+    }
+    let mut located = Span::locate_all(input, all).into_iter();
+    for (_, span) in &mut commands {
+        locate(span, &mut located);
+    }
+    let commands = commands
+        .into_iter()
+        .map(|(node, span)| SpannedCommand {
+            span: span.span,
+            node,
+            body: span.body,
+        })
+        .collect();
+    Ok((rest, SpannedLmmlAst(commands)))
+}
+
+/// コマンドを読み、`origin`の先頭からのバイト数で表した位置を付ける
+///
+/// ループやマクロの定義では本体の各コマンドの位置も求める。行番号と列番号は求めない。
+fn parse_spanned_command<'a>(
+    origin: &'a str,
+    input: &'a str,
+) -> IResult<&'a str, (LmmlCommand, CommandSpan), VerboseError<&'a str>> {
+    let at = |input: &str, body| CommandSpan {
+        span: Span {
+            file: 0,
+            offset: origin.offset(input),
+            line: 0,
+            column: 0,
+        },
+        body,
+    };
+    let command = |i| parse_spanned_command(origin, i);
+    let loop_break = map(recognize(char('/')), |i| {
+        (LmmlCommand::LoopBreak, at(i, Vec::new()))
+    });
+    let (rest, (node, body)) = alt((
+        map(loop_of(command, loop_break), |(body, count)| {
+            let (body, spans) = body.into_iter().unzip();
+            (LmmlCommand::Loop { body, count }, spans)
+        }),
+        map(define_macro_of(command), |(name, body)| {
+            let (body, spans) = body.into_iter().unzip();
+            (LmmlCommand::DefineMacro { name, body }, spans)
+        }),
+        map(parse_command, |node| (node, Vec::new())),
+    ))
+    .parse(input)?;
+    Ok((rest, (node, at(input, body))))
+}
+
 pub fn parse_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
     alt((
-        parse_note_command,
-        parse_rest_command,
-        parse_chord_command,
-        parse_n_command,
-        parse_octave_command,
-        parse_length_command,
-        parse_volume_command,
-        parse_tempo_command,
-        parse_waveform_command,
-        parse_channel_command,
-        parse_inc_octave_command,
-        parse_dec_octave_command,
+        alt((
+            parse_note_command,
+            parse_rest_command,
+            parse_chord_command,
+            parse_n_command,
+            parse_tie_command,
+            parse_tie_length_command,
+            parse_slide_command,
+        )),
+        alt((
+            parse_octave_command,
+            parse_length_command,
+            parse_volume_command,
+            parse_gate_command,
+            parse_pan_command,
+            parse_tempo_command,
+            parse_waveform_command,
+            parse_glide_command,
+            parse_channel_command,
+            parse_inc_octave_command,
+            parse_dec_octave_command,
+        )),
+        alt((
+            parse_define_envelope_command,
+            parse_envelope_command,
+            parse_define_wavetable_command,
+            parse_define_fm_command,
+            parse_define_sample_command,
+            parse_effect_command,
+            parse_filter_command,
+            parse_filter_envelope_command,
+            parse_vibrato_command,
+        )),
+        alt((
+            parse_loop_command,
+            parse_define_macro_command,
+            parse_call_macro_command,
+            parse_include_command,
+        )),
     ))
     .parse(input)
 }
@@ -125,6 +244,20 @@ pub fn parse_volume_command(input: &str) -> IResult<&str, LmmlCommand, VerboseEr
     .parse(input)
 }
 
+pub fn parse_gate_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(preceded(one_of("Qq"), parse_number), |n| {
+        LmmlCommand::SetGate(n)
+    })
+    .parse(input)
+}
+
+pub fn parse_pan_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(preceded(one_of("Pp"), parse_number), |n| {
+        LmmlCommand::SetPan(n)
+    })
+    .parse(input)
+}
+
 pub fn parse_tempo_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
     map(preceded(one_of("Tt"), parse_number), |n| {
         LmmlCommand::SetTempo(n)
@@ -139,6 +272,257 @@ pub fn parse_waveform_command(input: &str) -> IResult<&str, LmmlCommand, Verbose
     .parse(input)
 }
 
+pub fn parse_glide_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(preceded(pair(char('@'), one_of("Gg")), parse_number), |n| {
+        LmmlCommand::SetGlide(n)
+    })
+    .parse(input)
+}
+
+/// `{1, 2, 3}`のような数値の並び
+pub fn parse_number_list(input: &str) -> IResult<&str, Vec<u32>, VerboseError<&str>> {
+    delimited(
+        pair(char('{'), multispace0),
+        separated_list1(delimited(multispace0, char(','), multispace0), parse_number),
+        pair(multispace0, char('}')),
+    )
+    .parse(input)
+}
+
+pub fn parse_define_envelope_command(
+    input: &str,
+) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        pair(
+            terminated(
+                preceded(pair(char('@'), one_of("Ee")), parse_number),
+                pair(multispace0, char('=')),
+            ),
+            context(
+                "エンベロープは{attack,decay,sustain,release}の4つの数値で指定してください",
+                cut(preceded(
+                    multispace0,
+                    map_opt(parse_number_list, |v| <[u32; 4]>::try_from(v).ok()),
+                )),
+            ),
+        ),
+        |(number, [attack_ms, decay_ms, sustain, release_ms])| LmmlCommand::DefineEnvelope {
+            number,
+            attack_ms,
+            decay_ms,
+            sustain,
+            release_ms,
+        },
+    )
+    .parse(input)
+}
+
+pub fn parse_envelope_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(preceded(pair(char('@'), one_of("Ee")), parse_number), |n| {
+        LmmlCommand::SetEnvelope(n)
+    })
+    .parse(input)
+}
+
+pub fn parse_define_wavetable_command(
+    input: &str,
+) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        pair(
+            terminated(
+                preceded(pair(char('@'), one_of("Ww")), parse_number),
+                pair(multispace0, char('=')),
+            ),
+            context(
+                "波形は{0,3,7,15}のように1つ以上の数値で指定してください",
+                cut(preceded(multispace0, parse_number_list)),
+            ),
+        ),
+        |(number, samples)| LmmlCommand::DefineWavetable { number, samples },
+    )
+    .parse(input)
+}
+
+/// `@d{time,feedback,mix}`、`@r{room,damp,mix}`、`@c{rate,depth,mix}`
+pub fn parse_effect_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        pair(
+            preceded(char('@'), one_of("DdRrCc")),
+            context(
+                "エフェクトは{...}の3つの数値で指定してください",
+                cut(preceded(
+                    multispace0,
+                    map_opt(parse_number_list, |v| <[u32; 3]>::try_from(v).ok()),
+                )),
+            ),
+        ),
+        |(kind, [a, b, mix])| match kind.to_ascii_lowercase() {
+            'd' => LmmlCommand::SetDelay(Delay {
+                time_ms: a,
+                feedback: b,
+                mix,
+            }),
+            'r' => LmmlCommand::SetReverb(Reverb {
+                room: a,
+                damp: b,
+                mix,
+            }),
+            _ => LmmlCommand::SetChorus(Chorus {
+                rate: a,
+                depth_ms: b,
+                mix,
+            }),
+        },
+    )
+    .parse(input)
+}
+
+/// `@l{kind,cutoff,resonance}`
+pub fn parse_filter_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        preceded(
+            pair(char('@'), one_of("Ll")),
+            context(
+                "フィルターは{種類(0～3),カットオフ周波数,レゾナンス}の3つの数値で指定してください",
+                cut(preceded(
+                    multispace0,
+                    map_opt(parse_number_list, |v| match v.as_slice() {
+                        [kind, cutoff_hz, resonance] => Some(Filter {
+                            kind: FilterKind::from_number(*kind)?,
+                            cutoff_hz: *cutoff_hz,
+                            resonance: *resonance,
+                        }),
+                        _ => None,
+                    }),
+                )),
+            ),
+        ),
+        LmmlCommand::SetFilter,
+    )
+    .parse(input)
+}
+
+/// `@m{envelope,depth}`
+pub fn parse_filter_envelope_command(
+    input: &str,
+) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        preceded(
+            pair(char('@'), one_of("Mm")),
+            context(
+                "フィルターエンベロープは{エンベロープの番号,深さ}の2つの数値で指定してください",
+                cut(preceded(
+                    multispace0,
+                    map_opt(parse_number_list, |v| <[u32; 2]>::try_from(v).ok()),
+                )),
+            ),
+        ),
+        |[envelope, depth]| LmmlCommand::SetFilterEnvelope { envelope, depth },
+    )
+    .parse(input)
+}
+
+/// `@v{depth,rate,delay}`
+pub fn parse_vibrato_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        preceded(
+            pair(char('@'), one_of("Vv")),
+            context(
+                "ビブラートは{深さ,速さ,遅延}の3つの数値で指定してください",
+                cut(preceded(
+                    multispace0,
+                    map_opt(parse_number_list, |v| <[u32; 3]>::try_from(v).ok()),
+                )),
+            ),
+        ),
+        |[depth, rate, delay_ms]| {
+            LmmlCommand::SetVibrato(Vibrato {
+                depth,
+                rate,
+                delay_ms,
+            })
+        },
+    )
+    .parse(input)
+}
+
+pub fn parse_define_fm_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        pair(
+            terminated(
+                preceded(pair(char('@'), one_of("Ff")), parse_number),
+                pair(multispace0, char('=')),
+            ),
+            context(
+                "FM音源の音色は{algorithm,feedback,...}の後に2つまたは4つのオペレーターの{ratio,level,attack,decay,sustain,release}を続けて指定してください",
+                cut(preceded(
+                    multispace0,
+                    map_opt(parse_number_list, |v| match v.as_slice() {
+                        [algorithm, feedback, operators @ ..]
+                            if operators.len() == 12 || operators.len() == 24 =>
+                        {
+                            let operators = operators
+                                .chunks_exact(6)
+                                .map(|c| <[u32; 6]>::try_from(c).unwrap())
+                                .collect();
+                            Some((*algorithm, *feedback, operators))
+                        }
+                        _ => None,
+                    }),
+                )),
+            ),
+        ),
+        |(number, (algorithm, feedback, operators))| LmmlCommand::DefineFm {
+            number,
+            algorithm,
+            feedback,
+            operators,
+        },
+    )
+    .parse(input)
+}
+
+pub fn parse_define_sample_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    let comma = || delimited(multispace0, char(','), multispace0);
+    map(
+        pair(
+            terminated(
+                preceded(pair(char('@'), one_of("Ss")), parse_number),
+                pair(multispace0, char('=')),
+            ),
+            context(
+                "音声ファイルは{\"file.wav\",root,start,end}のように指定してください(root以降は省略可能)",
+                cut(preceded(
+                    multispace0,
+                    map_opt(
+                        delimited(
+                            pair(char('{'), multispace0),
+                            pair(parse_string_literal, many0(preceded(comma(), parse_number))),
+                            pair(multispace0, char('}')),
+                        ),
+                        |(path, numbers)| {
+                            let (root, loop_frames) = match numbers.as_slice() {
+                                [] => (DEFAULT_SAMPLE_ROOT, None),
+                                [root] => (*root, None),
+                                [root, start, end] => (*root, Some((*start, *end))),
+                                _ => return None,
+                            };
+                            Some((path.to_string(), root, loop_frames))
+                        },
+                    ),
+                )),
+            ),
+        ),
+        |(number, (path, root, loop_frames))| LmmlCommand::DefineSample {
+            number,
+            path,
+            root,
+            loop_frames,
+        },
+    )
+    .parse(input)
+}
+
 pub fn parse_channel_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
     map(preceded(char(':'), parse_number), |n| {
         LmmlCommand::SetChannel(n)
@@ -154,6 +538,130 @@ pub fn parse_dec_octave_command(input: &str) -> IResult<&str, LmmlCommand, Verbo
     map(char('<'), |_| LmmlCommand::DecreaseOctave).parse(input)
 }
 
+pub fn parse_tie_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(char('&'), |_| LmmlCommand::Tie).parse(input)
+}
+
+pub fn parse_slide_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(char('~'), |_| LmmlCommand::Slide).parse(input)
+}
+
+pub fn parse_tie_length_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        preceded(char('^'), pair(opt(parse_number), parse_dot)),
+        |(length, is_dotted)| LmmlCommand::TieLength { length, is_dotted },
+    )
+    .parse(input)
+}
+
+pub fn parse_loop_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        loop_of(parse_command, value(LmmlCommand::LoopBreak, char('/'))),
+        |(body, count)| LmmlCommand::Loop { body, count },
+    )
+    .parse(input)
+}
+
+/// `/:`と`:/`で囲まれたループの本体と繰り返す回数
+///
+/// 本体の各コマンドは`command`で、ループを抜ける`/`は`loop_break`で読む。
+/// `/`は1つのループの本体に1つまでしか書けない。
+fn loop_of<'a, O>(
+    command: impl Parser<&'a str, Output = O, Error = VerboseError<&'a str>> + Clone,
+    loop_break: impl Parser<&'a str, Output = O, Error = VerboseError<&'a str>>,
+) -> impl Parser<&'a str, Output = (Vec<O>, Option<u32>), Error = VerboseError<&'a str>> {
+    let commands = |command| many0(delimited(multispace0, command, multispace0));
+    map(
+        pair(
+            delimited(
+                tag("/:"),
+                pair(
+                    commands(command.clone()),
+                    opt(pair(
+                        delimited(multispace0, loop_break, multispace0),
+                        commands(command),
+                    )),
+                ),
+                context(
+                    "ループを抜ける/は1つのループに1つまでしか書けません",
+                    cut(not(preceded(multispace0, pair(char('/'), not(char(':')))))),
+                ),
+            ),
+            preceded(tag(":/"), opt(parse_number)),
+        ),
+        |((mut body, rest), count)| {
+            if let Some((loop_break, rest)) = rest {
+                body.push(loop_break);
+                body.extend(rest);
+            }
+            (body, count)
+        },
+    )
+}
+
+pub fn parse_macro_name(input: &str) -> IResult<&str, String, VerboseError<&str>> {
+    map(
+        recognize(pair(
+            satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        )),
+        str::to_string,
+    )
+    .parse(input)
+}
+
+pub fn parse_define_macro_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(define_macro_of(parse_command), |(name, body)| {
+        LmmlCommand::DefineMacro { name, body }
+    })
+    .parse(input)
+}
+
+/// `!名前 = ...;`のマクロの名前と本体。本体の各コマンドは`command`で読む。
+fn define_macro_of<'a, O>(
+    command: impl Parser<&'a str, Output = O, Error = VerboseError<&'a str>>,
+) -> impl Parser<&'a str, Output = (String, Vec<O>), Error = VerboseError<&'a str>> {
+    pair(
+        delimited(char('!'), parse_macro_name, (multispace0, char('='))),
+        terminated(
+            many0(delimited(multispace0, command, multispace0)),
+            char(';'),
+        ),
+    )
+}
+
+pub fn parse_call_macro_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        preceded(char('!'), parse_macro_name),
+        LmmlCommand::CallMacro,
+    )
+    .parse(input)
+}
+
+pub fn parse_include_command(input: &str) -> IResult<&str, LmmlCommand, VerboseError<&str>> {
+    map(
+        preceded(
+            tag("#include"),
+            context(
+                "#includeの後にはファイル名を\"\"で囲んで書いてください",
+                cut(preceded(multispace0, parse_string_literal)),
+            ),
+        ),
+        |path: &str| LmmlCommand::Include(path.to_string()),
+    )
+    .parse(input)
+}
+
+/// `"`で囲まれたファイル名など。改行を含むことはできない。
+pub fn parse_string_literal(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    delimited(
+        char('"'),
+        take_while(|c| !matches!(c, '"' | '\n' | '\r')),
+        char('"'),
+    )
+    .parse(input)
+}
+
 pub fn parse_note_char(input: &str) -> IResult<&str, NoteChar, VerboseError<&str>> {
     map(one_of("CDEFGABcdefgab"), |c| match c {
         'C' | 'c' => NoteChar::C,
@@ -184,9 +692,13 @@ pub fn parse_dot(input: &str) -> IResult<&str, bool, VerboseError<&str>> {
 }
 
 pub fn parse_number(input: &str) -> IResult<&str, u32, VerboseError<&str>> {
-    map(many1(one_of("0123456789")), |s| {
-        s.iter().collect::<String>().parse::<u32>().unwrap()
-    })
+    // 数字が無い場合は他の解釈を試せるように通常のエラーとし、
+    // 数字が大きすぎる場合はそこで解析を打ち切る
+    peek(digit1).parse(input)?;
+    context(
+        "数値が大きすぎます (上限は4294967295)",
+        cut(map_res(digit1, |s: &str| s.parse::<u32>())),
+    )
     .parse(input)
 }
 
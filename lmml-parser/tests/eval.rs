@@ -0,0 +1,560 @@
+use lmml::{
+    ast::{EvalEnv, EvalError, LmmlAst},
+    timeline::{Element, LmmlTimeline, Note, NoteType},
+};
+use lmml_parser::parse_lmml;
+
+fn parse(input: &str) -> LmmlAst {
+    let (rest, ast) = parse_lmml(input).unwrap();
+    assert!(rest.is_empty());
+    ast
+}
+
+fn eval(input: &str) -> Result<LmmlTimeline, EvalError> {
+    parse(input).to_timeline(&mut EvalEnv::default())
+}
+
+/// `channel`の音だけを取り出す
+fn notes(timeline: &LmmlTimeline, channel: usize) -> Vec<&Note> {
+    timeline.timeline[channel]
+        .iter()
+        .filter_map(|e| match e {
+            Element::Note(note) => Some(note),
+            Element::Event(_) => None,
+        })
+        .collect()
+}
+
+/// チャンネル0の音の長さと鳴っている長さ
+fn lengths(input: &str) -> Result<Vec<(u32, u32)>, EvalError> {
+    let timeline = eval(input)?;
+    Ok(notes(&timeline, 0)
+        .iter()
+        .map(|note| (note.length_ms, note.sound_ms))
+        .collect())
+}
+
+/// READMEの「数値の限界について」に書かれた境界値
+#[test]
+fn limits() {
+    let eval = |input| eval(input).map(|_| ());
+
+    // オクターブ: 音の高さがノート番号0～127に収まる範囲
+    assert_eq!(eval("o0 < c"), Ok(()));
+    assert_eq!(eval("o0 < c-"), Err(EvalError::NoteNumberOutOfRange(-1)));
+    assert_eq!(eval("o0 << c"), Err(EvalError::NoteNumberOutOfRange(-12)));
+    assert_eq!(eval("o9 g"), Ok(()));
+    assert_eq!(eval("o9 g+"), Err(EvalError::NoteNumberOutOfRange(128)));
+    assert_eq!(eval("o9 [eg]"), Ok(()));
+    assert_eq!(eval("o9 [ge]"), Err(EvalError::NoteNumberOutOfRange(136)));
+    assert_eq!(
+        eval("o4294967295 >>> c"),
+        Err(EvalError::NoteNumberOutOfRange(i32::MAX as i64))
+    );
+    assert_eq!(
+        eval("o4294967295 c <<"),
+        Err(EvalError::NoteNumberOutOfRange(i32::MAX as i64))
+    );
+
+    // ボリューム: 下限0、上限なし
+    assert_eq!(eval("v0 c v4294967295 c"), Ok(()));
+
+    // Nコマンド: 0～127
+    assert_eq!(eval("n0 n127"), Ok(()));
+    assert_eq!(eval("n128"), Err(EvalError::NoteNumberOutOfRange(128)));
+
+    // テンポ: 下限1
+    assert_eq!(eval("t1 l1. c t4294967295 c"), Ok(()));
+    assert_eq!(eval("t0"), Err(EvalError::ZeroTempo));
+
+    // 音の長さ: 下限1
+    assert_eq!(eval("l1 c1 r1 [ce]1 l4294967295 c"), Ok(()));
+    assert_eq!(eval("l0"), Err(EvalError::ZeroLength));
+    assert_eq!(eval("l0."), Err(EvalError::ZeroLength));
+    assert_eq!(eval("c0"), Err(EvalError::ZeroLength));
+    assert_eq!(eval("r0."), Err(EvalError::ZeroLength));
+    assert_eq!(eval("[ceg]0"), Err(EvalError::ZeroLength));
+
+    // チャンネル番号: 0～15
+    assert_eq!(eval(":0 c :15 c"), Ok(()));
+    assert_eq!(eval(":16 c"), Err(EvalError::ChannelOutOfRange(16)));
+}
+
+#[test]
+fn length_of_longest_note() {
+    assert_eq!(lengths("t1 c1."), Ok(vec![(360000, 360000)]));
+}
+
+#[test]
+fn ties() {
+    assert_eq!(lengths("c4&c8"), Ok(vec![(750, 750)]));
+    assert_eq!(lengths("c4^8"), Ok(vec![(750, 750)]));
+    assert_eq!(lengths("l8 c4^^."), Ok(vec![(1125, 1125)]));
+    assert_eq!(lengths("c4 & t60 c4"), Ok(vec![(1500, 1500)]));
+    assert_eq!(lengths("c4&d8"), Ok(vec![(500, 500), (250, 250)]));
+    assert_eq!(lengths("c4&:1c8 :0c8"), Ok(vec![(750, 750)]));
+    assert_eq!(lengths("&c"), Err(EvalError::NothingToTie));
+    assert_eq!(lengths("^4"), Err(EvalError::NothingToTie));
+    assert_eq!(lengths("c4&"), Err(EvalError::NothingAfterTie));
+    assert_eq!(lengths("c4& :1c8"), Err(EvalError::NothingAfterTie));
+}
+
+#[test]
+fn gate() {
+    assert_eq!(lengths("c"), Ok(vec![(500, 500)]));
+    assert_eq!(
+        lengths("q4 c [ceg]8 n60"),
+        Ok(vec![(500, 250), (250, 125), (500, 250)])
+    );
+    assert_eq!(lengths("q1 c q8 d"), Ok(vec![(500, 62), (500, 500)]));
+    assert_eq!(lengths("q4 c&c"), Ok(vec![(1000, 750)]));
+    assert_eq!(lengths("q4 c^4"), Ok(vec![(1000, 750)]));
+    assert_eq!(lengths("q4 c&d"), Ok(vec![(500, 500), (500, 250)]));
+    assert_eq!(lengths(":1 q4 :0 c"), Ok(vec![(500, 500)]));
+    assert_eq!(lengths("q0"), Err(EvalError::GateOutOfRange(0)));
+    assert_eq!(lengths("q9"), Err(EvalError::GateOutOfRange(9)));
+}
+
+#[test]
+fn envelope() {
+    use lmml::oscillator::Envelope;
+
+    let timeline = eval("c @e1={1,2,3,4} @e1 d :1 c @e0 d").unwrap();
+    let envelopes = |channel| {
+        notes(&timeline, channel)
+            .iter()
+            .filter_map(|note| match note.note_type {
+                NoteType::Single { envelope, .. } => Some(envelope),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let adsr = Envelope::Adsr {
+        attack_ms: 1,
+        decay_ms: 2,
+        sustain: 3,
+        release_ms: 4,
+    };
+    assert_eq!(envelopes(0), vec![Envelope::Fadeout, adsr]);
+    assert_eq!(envelopes(1), vec![Envelope::Fadeout, Envelope::Fadeout]);
+
+    assert_eq!(eval("@e2"), Err(EvalError::UndefinedEnvelope(2)));
+    assert_eq!(eval("@e0={1,2,3,4}"), Err(EvalError::ReservedEnvelope(0)));
+    assert_eq!(
+        eval("@e1={1,2,101,4}"),
+        Err(EvalError::SustainOutOfRange(101))
+    );
+    assert!(matches!(
+        parse_lmml("@e1={1,2,3}"),
+        Err(nom::Err::Failure(_))
+    ));
+}
+
+#[test]
+fn percussion() {
+    use lmml::oscillator::Drum;
+
+    let timeline = eval("@7 c o6 d+ [ceg] c&c @0 c").unwrap();
+    let notes: Vec<_> = notes(&timeline, 0)
+        .into_iter()
+        .map(|note| (note.length_ms, &note.note_type))
+        .collect();
+    let drums = |drums: &[Drum]| NoteType::Drum {
+        drums: drums.to_vec(),
+        volume: 20.0,
+        envelope: Default::default(),
+    };
+    assert_eq!(notes.len(), 5);
+    assert_eq!(notes[0], (500, &drums(&[Drum::Kick])));
+    assert_eq!(notes[1], (500, &drums(&[Drum::Snare])));
+    assert_eq!(
+        notes[2],
+        (500, &drums(&[Drum::Kick, Drum::ClosedHiHat, Drum::LowTom]))
+    );
+    assert_eq!(notes[3], (1000, &drums(&[Drum::Kick])));
+    assert!(matches!(notes[4].1, NoteType::Single { .. }));
+}
+
+#[test]
+fn pan() {
+    use lmml::timeline::Event;
+
+    let timeline = eval("c p0 d :1 p127").unwrap();
+    assert!(matches!(
+        timeline.timeline[0][1],
+        Element::Event(Event::ChangePan(0))
+    ));
+    assert_eq!(
+        timeline.timeline[1],
+        vec![Element::Event(Event::ChangePan(127))]
+    );
+    assert_eq!(eval("p128"), Err(EvalError::PanOutOfRange(128)));
+}
+
+#[test]
+fn effects() {
+    use lmml::{
+        ast::EffectParameter,
+        effect::{Chorus, Delay, Reverb},
+        timeline::Event,
+    };
+
+    let timeline = eval("@d{250,40,30} c @R{50,60,20} @c{5,3,50}").unwrap();
+    assert_eq!(
+        timeline.timeline[0][0],
+        Element::Event(Event::ChangeDelay(Delay {
+            time_ms: 250,
+            feedback: 40,
+            mix: 30
+        }))
+    );
+    assert_eq!(
+        timeline.timeline[0][2..],
+        [
+            Element::Event(Event::ChangeReverb(Reverb {
+                room: 50,
+                damp: 60,
+                mix: 20
+            })),
+            Element::Event(Event::ChangeChorus(Chorus {
+                rate: 5,
+                depth_ms: 3,
+                mix: 50
+            })),
+        ]
+    );
+    assert!(parse_lmml("@d{1,2}").is_err());
+    assert_eq!(
+        eval("@d{10,96,50}"),
+        Err(EvalError::EffectOutOfRange {
+            parameter: EffectParameter::DelayFeedback,
+            value: 96,
+        })
+    );
+    assert!(eval("@c{5,21,50}").is_err());
+    assert!(eval("@r{50,50,101}").is_err());
+}
+
+#[test]
+fn filter() {
+    use lmml::{
+        filter::{Filter, FilterEnvelope, FilterKind},
+        oscillator::Envelope,
+        timeline::Event,
+    };
+
+    let timeline = eval("@l{2,800,30} c @m{0,24}").unwrap();
+    assert_eq!(
+        timeline.timeline[0][0],
+        Element::Event(Event::ChangeFilter(Filter {
+            kind: FilterKind::HighPass,
+            cutoff_hz: 800,
+            resonance: 30
+        }))
+    );
+    assert_eq!(
+        timeline.timeline[0][2],
+        Element::Event(Event::ChangeFilterEnvelope(FilterEnvelope {
+            envelope: Envelope::Fadeout,
+            depth: 24
+        }))
+    );
+    let timeline = eval("@e1={0,100,50,0} @m{1,12}").unwrap();
+    assert!(matches!(
+        timeline.timeline[0][0],
+        Element::Event(Event::ChangeFilterEnvelope(FilterEnvelope {
+            envelope: Envelope::Adsr { sustain: 50, .. },
+            depth: 12
+        }))
+    ));
+    assert!(parse_lmml("@l{4,800,30}").is_err());
+    assert!(parse_lmml("@m{1}").is_err());
+    assert!(eval("@l{1,20001,0}").is_err());
+    assert!(eval("@l{1,800,101}").is_err());
+    assert_eq!(eval("@m{2,12}"), Err(EvalError::UndefinedEnvelope(2)));
+}
+
+#[test]
+fn vibrato() {
+    use lmml::oscillator::Vibrato;
+
+    let timeline = eval("c @v{30,55,200} [ce] @v{0,0,0} d").unwrap();
+    let vibratos: Vec<_> = notes(&timeline, 0)
+        .iter()
+        .map(|note| match note.note_type {
+            NoteType::Single { vibrato, .. } | NoteType::Chord { vibrato, .. } => vibrato,
+            _ => unreachable!(),
+        })
+        .collect();
+    let vibrato = Vibrato {
+        depth: 30,
+        rate: 55,
+        delay_ms: 200,
+    };
+    assert_eq!(
+        vibratos,
+        vec![Vibrato::default(), vibrato, Vibrato::default()]
+    );
+    assert!(parse_lmml("@v{30,55}").is_err());
+    assert!(eval("@v{1201,55,0}").is_err());
+    assert!(eval("@v{30,201,0}").is_err());
+}
+
+#[test]
+fn slide() {
+    use lmml::timeline::Slide;
+
+    let slides = |input| {
+        notes(&eval(input).unwrap(), 0)
+            .iter()
+            .filter_map(|note| match note.note_type {
+                NoteType::Single { slide, .. } => Some(slide),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let a = 440.0;
+    let c = lmml::ast::notenumber_to_hz(72);
+    // `~`を付けた音だけが鳴っている間かけて変わる
+    assert_eq!(
+        slides("l4 a >c ~c ~< a"),
+        vec![
+            None,
+            None,
+            None,
+            Some(Slide {
+                from_hz: c,
+                time_ms: 500
+            })
+        ]
+    );
+    // `@G`で全ての音が指定した時間で変わる。休符を挟んでも直前の音から変わる。
+    assert_eq!(
+        slides("@g100 a >c r ~c @g0 < a"),
+        vec![
+            None,
+            Some(Slide {
+                from_hz: a,
+                time_ms: 100
+            }),
+            None,
+            None
+        ]
+    );
+    // タイでつないだ音は1つの音のまま
+    assert_eq!(slides("@g100 a & a"), vec![None]);
+    // ポルタメントで変わる音にもタイでつなげる
+    let to_c = Some(Slide {
+        from_hz: a,
+        time_ms: 100,
+    });
+    assert_eq!(slides("@g100 a >c & c"), vec![None, to_c]);
+    // `~`の音はタイでつなぐ前の長さで変わり、つないだ分は変わった後の高さのまま
+    assert_eq!(
+        slides("a ~>c & c"),
+        vec![None, to_c.map(|s| Slide { time_ms: 500, ..s })]
+    );
+    // FM音源の音色は高さを変えない
+    assert_eq!(
+        slides("@f10={0,0,1,1,1,1,1,1,1,1,1,1,1,1} @10 a ~>c"),
+        vec![None, None]
+    );
+    assert_eq!(eval("~c"), Err(EvalError::NothingToSlide));
+    assert_eq!(eval("@7 c ~c"), Err(EvalError::NothingToSlide));
+    assert_eq!(eval("c ~"), Err(EvalError::NothingAfterSlide));
+}
+
+#[test]
+fn wavetable() {
+    use lmml::timeline::Instrument;
+
+    let timeline = eval("@w10={0,5,10} @10 c :1 @w10={1,1} @10 c :0 c").unwrap();
+    let instrument = |note: &Note| match note.note_type {
+        NoteType::Single {
+            waveform: 10,
+            ref instrument,
+            ..
+        } => instrument.as_deref().cloned(),
+        _ => None,
+    };
+    // 定義し直す前に置いた音は元の波形のまま
+    assert_eq!(
+        instrument(notes(&timeline, 0)[0]),
+        Some(Instrument::Wavetable(vec![-1.0, 0.0, 1.0]))
+    );
+    assert_eq!(
+        instrument(notes(&timeline, 1)[0]),
+        Some(Instrument::Wavetable(vec![0.0, 0.0]))
+    );
+    assert_eq!(
+        instrument(notes(&timeline, 0)[1]),
+        Some(Instrument::Wavetable(vec![0.0, 0.0]))
+    );
+    assert_eq!(
+        Instrument::wavetable(&[0, 5, 10, 5]),
+        Instrument::Wavetable(vec![-1.0, 0.0, 1.0, 0.0])
+    );
+
+    assert!(eval("@9").is_ok());
+    assert_eq!(eval("@10"), Err(EvalError::UndefinedInstrument(10)));
+    assert_eq!(eval("@w9={1,2}"), Err(EvalError::ReservedInstrument(9)));
+    assert!(matches!(parse_lmml("@w10={}"), Err(nom::Err::Failure(_))));
+}
+
+#[test]
+fn fm() {
+    use lmml::{
+        oscillator::{Envelope, FmOperator, FmVoice},
+        timeline::Instrument,
+    };
+
+    let mut env = EvalEnv::default();
+    parse("@f10={0,7, 1,50,1,2,3,4, 0,100,5,6,7,8} @10 c")
+        .to_timeline(&mut env)
+        .unwrap();
+    let operator =
+        |ratio, level, [attack_ms, decay_ms, sustain, release_ms]: [u32; 4]| FmOperator {
+            ratio,
+            level,
+            envelope: Envelope::Adsr {
+                attack_ms,
+                decay_ms,
+                sustain,
+                release_ms,
+            },
+        };
+    assert_eq!(
+        env.instruments.get(&10).map(|i| &**i),
+        Some(&Instrument::Fm(FmVoice {
+            algorithm: 0,
+            feedback: 7,
+            operators: vec![
+                operator(1, 50, [1, 2, 3, 4]),
+                operator(0, 100, [5, 6, 7, 8])
+            ],
+        }))
+    );
+
+    // 定義し直す前に置いた音は元の音色のまま
+    let timeline =
+        eval("@f10={0,7,1,1,1,1,1,1,1,1,1,1,1,1} @10 c @f10={0,3,1,1,1,1,1,1,1,1,1,1,1,1} c")
+            .unwrap();
+    let feedbacks: Vec<_> = notes(&timeline, 0)
+        .iter()
+        .filter_map(|note| match note.note_type {
+            NoteType::Single { ref instrument, .. } => match instrument.as_deref() {
+                Some(Instrument::Fm(voice)) => Some(voice.feedback),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    assert_eq!(feedbacks, vec![7, 3]);
+
+    assert_eq!(
+        eval("@f10={2,0,1,1,1,1,1,1,1,1,1,1,1,1}"),
+        Err(EvalError::InvalidFmVoice {
+            operators: 2,
+            algorithm: 2,
+            feedback: 0
+        })
+    );
+    assert_eq!(
+        eval("@f10={0,8,1,1,1,1,1,1,1,1,1,1,1,1}"),
+        Err(EvalError::InvalidFmVoice {
+            operators: 2,
+            algorithm: 0,
+            feedback: 8
+        })
+    );
+    assert_eq!(
+        eval("@f10={0,0,1,1,1,1,101,1,1,1,1,1,1,1}"),
+        Err(EvalError::SustainOutOfRange(101))
+    );
+    assert_eq!(
+        eval("@f1={0,0,1,1,1,1,1,1,1,1,1,1,1,1}"),
+        Err(EvalError::ReservedInstrument(1))
+    );
+    assert!(matches!(
+        parse_lmml("@f10={0,0,1,1,1,1,1,1}"),
+        Err(nom::Err::Failure(_))
+    ));
+}
+
+#[test]
+fn macros() {
+    let timeline = |input| eval(input).unwrap();
+    assert_eq!(timeline("!A = c d; !A !A"), timeline("c d c d"));
+    assert_eq!(timeline("!A = o5; !B = c !A; !B !B"), timeline("c o5 c"));
+    assert_eq!(timeline("!A = c; :1 !A"), timeline(":1 c"));
+    assert_eq!(timeline("!A = c; !A = d; !A"), timeline("d"));
+    assert_eq!(eval("!B"), Err(EvalError::UndefinedMacro("B".to_string())));
+    assert_eq!(
+        eval("!a = c; !A"),
+        Err(EvalError::UndefinedMacro("A".to_string()))
+    );
+    assert_eq!(
+        eval("!A = !B; !B = c !A; !A"),
+        Err(EvalError::RecursiveMacro("A".to_string()))
+    );
+    assert_eq!(
+        eval("!A = !A; !A"),
+        Err(EvalError::RecursiveMacro("A".to_string()))
+    );
+
+    let mut env = EvalEnv::default();
+    parse("!A = c;").to_timeline(&mut env).unwrap();
+    assert_eq!(parse("!A").to_timeline(&mut env).unwrap(), timeline("c"));
+}
+
+#[test]
+fn loops() {
+    let notes = |input| {
+        notes(&eval(input).unwrap(), 0)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(notes("/: c d / e :/3").len(), 8);
+    assert_eq!(notes("/: c d :/"), notes("c d c d"));
+    assert_eq!(notes("/: c :/0"), vec![]);
+    assert_eq!(
+        notes("o3 /: c > /: d8 :/ l8 :/"),
+        notes("o3 c4 > d8 d8 l8 c > d d")
+    );
+    assert_eq!(notes("/: c & :/ c"), notes("c2."));
+    // `/`は1つのループに1つまで。入れ子のループはそれぞれ1つずつ書ける
+    assert_eq!(notes("/: c / /: d / e :/ :/"), notes("c d e d c"));
+    for input in ["/: c / d / e :/3", "/: / c / :/", "/: c /: d :/ / e / :/"] {
+        assert!(
+            matches!(parse_lmml(input), Err(nom::Err::Failure(_))),
+            "{}",
+            input
+        );
+        assert!(
+            matches!(
+                lmml_parser::parse_lmml_spanned(input),
+                Err(nom::Err::Failure(_))
+            ),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn expansion_limit() {
+    assert_eq!(eval("/: c :/4294967295"), Err(EvalError::TooManySteps));
+    assert_eq!(eval("/: o4 :/4294967295"), Err(EvalError::TooManySteps));
+    assert_eq!(
+        eval("/: /: /: /: c :/100 :/100 :/100 :/100"),
+        Err(EvalError::TooManySteps)
+    );
+    assert_eq!(
+        eval(
+            "!A = c c c c c c c c c c; !B = !A !A !A !A !A !A !A !A !A !A; !C = !B !B !B !B !B !B !B !B !B !B; !D = !C !C !C !C !C !C !C !C !C !C; /: !D :/1000"
+        ),
+        Err(EvalError::TooManySteps)
+    );
+    assert!(eval("/: c :/1000").is_ok());
+}
@@ -1,11 +1,129 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display, sync::Arc};
 
-use crate::timeline::{Element, Event, LmmlTimeline, Note, NoteType};
+use crate::{
+    effect::{
+        Chorus, Delay, Effects, MAX_CHORUS_DEPTH_MS, MAX_CHORUS_RATE, MAX_DELAY_MS, MAX_FEEDBACK,
+        Reverb,
+    },
+    filter::{Filter, FilterEnvelope, MAX_CUTOFF_HZ, MAX_FILTER_DEPTH},
+    oscillator::{
+        Drum, Envelope, FmOperator, FmVoice, MAX_VIBRATO_DEPTH, MAX_VIBRATO_RATE, SampleData,
+        SampleInstrument, Vibrato,
+    },
+    printer::PrintOptions,
+    timeline::{Element, Event, Instrument, LmmlTimeline, Note, NoteType, Slide},
+};
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct LmmlAst(pub Vec<LmmlCommand>);
 
+/// ソースコード上の位置
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Span {
+    /// ファイルの番号。`#include`で読み込まれたコマンドを区別するために使われ、通常は0
+    pub file: usize,
+    /// 先頭からのバイト数
+    pub offset: usize,
+    /// 行番号(1始まり)
+    pub line: usize,
+    /// 列番号(1始まり、文字単位)
+    pub column: usize,
+}
+
+impl Span {
+    /// `source`の先頭から`offset`バイト目の位置を求める
+    pub fn locate(source: &str, offset: usize) -> Self {
+        Self::locate_all(source, [offset])[0]
+    }
+
+    /// 昇順に並んだ`offsets`のそれぞれの位置を、`source`を1回だけ走査して求める
+    pub fn locate_all(source: &str, offsets: impl IntoIterator<Item = usize>) -> Vec<Self> {
+        let mut chars = source.char_indices().peekable();
+        let (mut line, mut column) = (1, 1);
+        offsets
+            .into_iter()
+            .map(|offset| {
+                while let Some((_, c)) = chars.next_if(|(i, _)| *i < offset) {
+                    if c == '\n' {
+                        line += 1;
+                        column = 1;
+                    } else {
+                        column += 1;
+                    }
+                }
+                Self {
+                    file: 0,
+                    offset,
+                    line,
+                    column,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// ソースコード上の位置の情報を伴う値
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Spanned<T> {
+    pub span: Span,
+    pub node: T,
+}
+
+/// ループやマクロの定義の本体にあるコマンドの位置
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CommandSpan {
+    pub span: Span,
+    /// このコマンドがループやマクロの定義なら、本体の各コマンドの位置
+    pub body: Vec<Self>,
+}
+
+/// ソースコード上の位置を付けたコマンド
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpannedCommand {
+    pub span: Span,
+    pub node: LmmlCommand,
+    /// `node`がループやマクロの定義なら、本体の各コマンドの位置。本体のコマンドと同じ順に並ぶ。
+    pub body: Vec<CommandSpan>,
+}
+
+impl SpannedCommand {
+    /// ループやマクロの定義の本体に入れるときの位置
+    pub fn to_command_span(&self) -> CommandSpan {
+        CommandSpan {
+            span: self.span,
+            body: self.body.clone(),
+        }
+    }
+}
+
+/// 各コマンドにソースコード上の位置を付けた[`LmmlAst`]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpannedLmmlAst(pub Vec<SpannedCommand>);
+
+impl SpannedLmmlAst {
+    pub fn to_ast(&self) -> LmmlAst {
+        LmmlAst(self.0.iter().map(|c| c.node.clone()).collect())
+    }
+}
+
+impl From<SpannedLmmlAst> for LmmlAst {
+    fn from(ast: SpannedLmmlAst) -> Self {
+        Self(ast.0.into_iter().map(|c| c.node).collect())
+    }
+}
+
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum LmmlCommand {
@@ -28,11 +146,119 @@ pub enum LmmlCommand {
     SetOctave(u32),
     SetLength(u32, bool),
     SetVolume(u32),
+    /// `q`: ゲートタイムを1～8で設定する
+    SetGate(u32),
+    /// `p`: 左右の定位を0～127で設定する
+    SetPan(u32),
+    /// `@d{time,feedback,mix}`: ディレイを設定する
+    SetDelay(Delay),
+    /// `@r{room,damp,mix}`: リバーブを設定する
+    SetReverb(Reverb),
+    /// `@c{rate,depth,mix}`: コーラスを設定する
+    SetChorus(Chorus),
+    /// `@l{kind,cutoff,resonance}`: フィルターを設定する
+    SetFilter(Filter),
+    /// `@m{envelope,depth}`: 音符ごとにカットオフ周波数を`envelope`番のエンベロープに従って動かす
+    SetFilterEnvelope {
+        envelope: u32,
+        depth: u32,
+    },
+    /// `@v{depth,rate,delay}`: ビブラートを設定する
+    SetVibrato(Vibrato),
+    /// `@g`: ポルタメントの時間をミリ秒で設定する。0ならポルタメントしない。
+    SetGlide(u32),
+    /// `@e<number>={attack,decay,sustain,release}`: エンベロープを定義する
+    DefineEnvelope {
+        number: u32,
+        attack_ms: u32,
+        decay_ms: u32,
+        sustain: u32,
+        release_ms: u32,
+    },
+    /// `@e<number>`: エンベロープを選択する
+    SetEnvelope(u32),
+    /// `@w<number>={...}`: 1周期分の波形を定義する。`@<number>`で選択できる。
+    DefineWavetable {
+        number: u32,
+        samples: Vec<u32>,
+    },
+    /// `@f<number>={algorithm,feedback,...}`: FM音源の音色を定義する。`@<number>`で選択できる。
+    ///
+    /// `operators`は各オペレーターの`[倍率,レベル,アタック,ディケイ,サステイン,リリース]`。
+    DefineFm {
+        number: u32,
+        algorithm: u32,
+        feedback: u32,
+        operators: Vec<[u32; 6]>,
+    },
+    /// `@s<number>={"path",root,start,end}`: 音声ファイルの音色を定義する。`@<number>`で選択できる。
+    ///
+    /// `root`は元の音の高さを表すノート番号、`loop_frames`はループする範囲。
+    /// 評価する前に音声ファイルを読み込み、[`EvalEnv::samples`]に入れておく必要がある。
+    DefineSample {
+        number: u32,
+        path: String,
+        root: u32,
+        loop_frames: Option<(u32, u32)>,
+    },
     SetTempo(u32),
     SetWaveform(u32),
     SetChannel(u32),
     IncreaseOctave,
     DecreaseOctave,
+    /// `&`: 直前の音と次の音をつなげる。同じ高さの音ならタイ、異なる高さの音ならスラーになる。
+    Tie,
+    /// `~`: 次の音の高さを直前の音の高さから滑らかに変える
+    Slide,
+    /// `^`: 直前の音の長さを延ばす
+    TieLength {
+        length: Option<u32>,
+        is_dotted: bool,
+    },
+    /// `/: ... :/n`: `body`を`count`回(省略時は2回)繰り返す
+    Loop {
+        body: Vec<Self>,
+        count: Option<u32>,
+    },
+    /// `/`: ループの最後の繰り返しではここで抜ける
+    LoopBreak,
+    /// `!name = ...;`: マクロを定義する
+    DefineMacro {
+        name: String,
+        body: Vec<Self>,
+    },
+    /// `!name`: マクロを展開する
+    CallMacro(String),
+    /// `#include "path"`: 他のファイルを読み込む
+    ///
+    /// 評価する前に読み込んだファイルのコマンドで置き換えておく必要がある。
+    Include(String),
+}
+
+impl LmmlCommand {
+    /// チャンネルを変更する可能性があるコマンドかどうか。ループの中で変更する場合も含む。
+    pub fn sets_channel(&self) -> bool {
+        match self {
+            Self::SetChannel(_) | Self::Include(_) => true,
+            Self::Loop { body, .. } => body.iter().any(Self::sets_channel),
+            _ => false,
+        }
+    }
+
+    /// マクロ・エンベロープ・音色などの全てのチャンネルで共有されるものを定義する可能性があるコマンドかどうか。
+    /// ループの中で定義する場合も含む。
+    pub fn defines_shared(&self) -> bool {
+        match self {
+            Self::DefineMacro { .. }
+            | Self::DefineEnvelope { .. }
+            | Self::DefineWavetable { .. }
+            | Self::DefineFm { .. }
+            | Self::DefineSample { .. }
+            | Self::Include(_) => true,
+            Self::Loop { body, .. } => body.iter().any(Self::defines_shared),
+            _ => false,
+        }
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -55,7 +281,185 @@ pub enum NoteModifier {
     Natural,
 }
 
-const fn resolve_length(
+impl Display for LmmlAst {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_lmml(&PrintOptions::default()))
+    }
+}
+
+fn fmt_length(
+    f: &mut std::fmt::Formatter<'_>,
+    length: Option<u32>,
+    is_dotted: bool,
+) -> std::fmt::Result {
+    if let Some(length) = length {
+        write!(f, "{}", length)?;
+    }
+    if is_dotted {
+        write!(f, ".")?;
+    }
+    Ok(())
+}
+
+impl Display for LmmlCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Note {
+                note,
+                modifier,
+                length,
+                is_dotted,
+            } => {
+                write!(f, "{}{}", note, modifier)?;
+                fmt_length(f, *length, *is_dotted)
+            }
+            Self::Rest { length, is_dotted } => {
+                write!(f, "r")?;
+                fmt_length(f, *length, *is_dotted)
+            }
+            Self::Chord {
+                notes,
+                length,
+                is_dotted,
+            } => {
+                write!(f, "[")?;
+                for (note, modifier) in notes {
+                    write!(f, "{}{}", note, modifier)?;
+                }
+                write!(f, "]")?;
+                fmt_length(f, *length, *is_dotted)
+            }
+            Self::NoteNumber(n) => write!(f, "n{}", n),
+            Self::SetOctave(o) => write!(f, "o{}", o),
+            Self::SetLength(l, d) => write!(f, "l{}{}", l, if *d { "." } else { "" }),
+            Self::SetVolume(v) => write!(f, "v{}", v),
+            Self::SetGate(q) => write!(f, "q{}", q),
+            Self::SetPan(p) => write!(f, "p{}", p),
+            Self::SetDelay(d) => write!(f, "@d{{{},{},{}}}", d.time_ms, d.feedback, d.mix),
+            Self::SetReverb(r) => write!(f, "@r{{{},{},{}}}", r.room, r.damp, r.mix),
+            Self::SetChorus(c) => write!(f, "@c{{{},{},{}}}", c.rate, c.depth_ms, c.mix),
+            Self::SetFilter(filter) => write!(
+                f,
+                "@l{{{},{},{}}}",
+                filter.kind.number(),
+                filter.cutoff_hz,
+                filter.resonance
+            ),
+            Self::SetFilterEnvelope { envelope, depth } => {
+                write!(f, "@m{{{},{}}}", envelope, depth)
+            }
+            Self::SetVibrato(v) => write!(f, "@v{{{},{},{}}}", v.depth, v.rate, v.delay_ms),
+            Self::SetGlide(ms) => write!(f, "@g{}", ms),
+            Self::DefineEnvelope {
+                number,
+                attack_ms,
+                decay_ms,
+                sustain,
+                release_ms,
+            } => write!(
+                f,
+                "@e{}={{{},{},{},{}}}",
+                number, attack_ms, decay_ms, sustain, release_ms
+            ),
+            Self::SetEnvelope(n) => write!(f, "@e{}", n),
+            Self::DefineWavetable { number, samples } => {
+                write!(f, "@w{}={{", number)?;
+                for (i, sample) in samples.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", sample)?;
+                }
+                write!(f, "}}")
+            }
+            Self::DefineFm {
+                number,
+                algorithm,
+                feedback,
+                operators,
+            } => {
+                write!(f, "@f{}={{{},{}", number, algorithm, feedback)?;
+                for operator in operators {
+                    for n in operator {
+                        write!(f, ",{}", n)?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Self::DefineSample {
+                number,
+                path,
+                root,
+                loop_frames,
+            } => {
+                write!(f, "@s{}={{\"{}\",{}", number, path, root)?;
+                if let Some((start, end)) = loop_frames {
+                    write!(f, ",{},{}", start, end)?;
+                }
+                write!(f, "}}")
+            }
+            Self::SetTempo(t) => write!(f, "t{}", t),
+            Self::SetWaveform(n) => write!(f, "@{}", n),
+            Self::SetChannel(n) => write!(f, ":{}", n),
+            Self::IncreaseOctave => write!(f, ">"),
+            Self::DecreaseOctave => write!(f, "<"),
+            Self::Tie => write!(f, "&"),
+            Self::Slide => write!(f, "~"),
+            Self::TieLength { length, is_dotted } => {
+                write!(f, "^")?;
+                fmt_length(f, *length, *is_dotted)
+            }
+            Self::Loop { body, count } => {
+                write!(f, "/:")?;
+                for command in body {
+                    write!(f, " {}", command)?;
+                }
+                write!(f, " :/")?;
+                if let Some(n) = count {
+                    write!(f, "{}", n)?;
+                }
+                Ok(())
+            }
+            Self::LoopBreak => write!(f, "/"),
+            Self::DefineMacro { name, body } => {
+                write!(f, "!{} =", name)?;
+                for command in body {
+                    write!(f, " {}", command)?;
+                }
+                write!(f, ";")
+            }
+            Self::CallMacro(name) => write!(f, "!{}", name),
+            Self::Include(path) => write!(f, "#include \"{}\"", path),
+        }
+    }
+}
+
+impl Display for NoteChar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let c = match self {
+            Self::C => 'c',
+            Self::D => 'd',
+            Self::E => 'e',
+            Self::F => 'f',
+            Self::G => 'g',
+            Self::A => 'a',
+            Self::B => 'b',
+        };
+        write!(f, "{}", c)
+    }
+}
+
+impl Display for NoteModifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sharp => write!(f, "+"),
+            Self::Flat => write!(f, "-"),
+            Self::Natural => Ok(()),
+        }
+    }
+}
+
+pub(crate) const fn resolve_length(
     l_cmd_num: u32,
     l_cmd_dot: bool,
     num: Option<u32>,
@@ -70,10 +474,44 @@ const fn resolve_length(
     }
 }
 
-fn length_to_ms(tempo: u32, (length, is_dotted): (u32, bool)) -> u32 {
+/// 音符の長さをミリ秒単位に変換する
+///
+/// テンポと長さはともに1以上であるため、結果は最大でも`t1 l1.`の360000msに収まる。
+fn length_to_ms(tempo: u32, (length, is_dotted): (u32, bool)) -> Result<u32, EvalError> {
+    if tempo == 0 {
+        return Err(EvalError::ZeroTempo);
+    }
+    if length == 0 {
+        return Err(EvalError::ZeroLength);
+    }
     let length = length as f32;
     let dot = if is_dotted { 1.5 } else { 1.0 };
-    ((4.0 / length * 60.0 / tempo as f32 * 1000.0) * dot) as u32
+    Ok(((4.0 / length * 60.0 / tempo as f32 * 1000.0) * dot) as u32)
+}
+
+/// ポルタメントの設定を除いた音
+fn without_slide(note_type: &NoteType) -> NoteType {
+    let mut note_type = note_type.clone();
+    if let NoteType::Single { slide, .. } = &mut note_type {
+        *slide = None;
+    }
+    note_type
+}
+
+/// ノート番号が0～127の範囲内であることを確かめる
+const fn check_notenumber(notenumber: i32) -> Result<i32, EvalError> {
+    if notenumber < 0 || notenumber > 127 {
+        return Err(EvalError::NoteNumberOutOfRange(notenumber as i64));
+    }
+    Ok(notenumber)
+}
+
+/// エフェクトやフィルターの設定値が上限以下であることを確かめる
+const fn check_effect(parameter: EffectParameter, value: u32) -> Result<(), EvalError> {
+    if value > parameter.max() {
+        return Err(EvalError::EffectOutOfRange { parameter, value });
+    }
+    Ok(())
 }
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -81,6 +519,16 @@ fn length_to_ms(tempo: u32, (length, is_dotted): (u32, bool)) -> u32 {
 pub struct EvalEnv {
     pub current_channel: usize,
     pub channels: [ChannelEnv; 16],
+    /// 定義されたマクロ。全てのチャンネルで共有される。
+    pub macros: BTreeMap<String, Vec<LmmlCommand>>,
+    /// 定義されたエンベロープ。全てのチャンネルで共有される。
+    pub envelopes: BTreeMap<u32, Envelope>,
+    /// `@`コマンドで選択できるユーザー定義の音色。全てのチャンネルで共有される。
+    ///
+    /// 音色を定義し直しても、それまでに置いた音は定義し直す前の音色で鳴る。
+    pub instruments: BTreeMap<u32, Arc<Instrument>>,
+    /// 読み込まれた音声ファイル。`@s`コマンドのパスをキーとする。
+    pub samples: BTreeMap<String, SampleData>,
 }
 
 impl EvalEnv {
@@ -93,6 +541,15 @@ impl EvalEnv {
     }
 }
 
+/// 1回の評価で評価できるコマンドとループの繰り返しの数の上限
+pub const MAX_EVAL_STEPS: usize = 1_000_000;
+
+/// 音符を打楽器として鳴らす音色の番号
+pub const PERCUSSION: u32 = 7;
+
+/// 組み込みの音色のために予約されている番号の数。これ以上の番号はユーザーが定義できる。
+pub const RESERVED_INSTRUMENTS: u32 = 10;
+
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
 pub struct ChannelEnv {
@@ -101,20 +558,39 @@ pub struct ChannelEnv {
     pub is_dotted: bool,
     pub tempo: u32,
     pub volume: u32,
+    /// 音色の番号。[`PERCUSSION`]なら音符は打楽器を表す。
     pub waveform: u32,
+    /// ゲートタイム。音符の長さのうち`gate / 8`の間だけ音を鳴らす。
+    pub gate: u32,
+    /// 左右の定位。0が左端、64が中央、127が右端。
+    pub pan: u32,
+    /// ディレイ・リバーブ・コーラスの設定
+    pub effects: Effects,
+    /// 音にかけるフィルターの設定
+    pub filter: Filter,
+    /// 音符ごとにカットオフ周波数を動かすエンベロープ
+    pub filter_envelope: FilterEnvelope,
+    /// 音の高さを周期的に揺らすビブラートの設定
+    pub vibrato: Vibrato,
+    /// ポルタメントの時間(ミリ秒)。0なら`~`を付けた音だけを、鳴っている間かけて滑らかに変える。
+    pub glide_ms: u32,
+    /// エンベロープの番号。0は[`Envelope::Fadeout`]を表す。
+    pub envelope: u32,
 }
 
 impl Display for ChannelEnv {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "@{} v{} t{} l{}{} o{}",
+            "@{} v{} t{} l{}{} o{} q{} p{}",
             self.waveform,
             self.volume,
             self.tempo,
             self.length,
             if self.is_dotted { "." } else { "" },
-            self.octave
+            self.octave,
+            self.gate,
+            self.pan
         )
     }
 }
@@ -128,138 +604,826 @@ impl Default for ChannelEnv {
             tempo: 120,
             volume: 20,
             waveform: 0,
+            gate: 8,
+            pan: 64,
+            effects: Effects::default(),
+            filter: Filter::default(),
+            filter_envelope: FilterEnvelope::default(),
+            vibrato: Vibrato::default(),
+            glide_ms: 0,
+            envelope: 0,
         }
     }
 }
 
-impl LmmlAst {
-    pub fn to_timeline(&self, env: &mut EvalEnv) -> LmmlTimeline {
-        let mut elements = [
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-            Vec::new(),
-        ];
+/// 上限のあるエフェクト・フィルター・ビブラートの設定値
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EffectParameter {
+    DelayTime,
+    DelayFeedback,
+    DelayMix,
+    ReverbRoom,
+    ReverbDamp,
+    ReverbMix,
+    ChorusRate,
+    ChorusDepth,
+    ChorusMix,
+    FilterCutoff,
+    FilterResonance,
+    FilterEnvelopeDepth,
+    VibratoDepth,
+    VibratoRate,
+}
 
-        for command in self.0.iter() {
-            match command {
-                LmmlCommand::Note {
-                    note,
-                    modifier,
-                    length: l,
-                    is_dotted,
-                } => elements[env.current_channel].push(Element::Note(Note {
-                    note_type: NoteType::Single {
-                        hz: note.to_hz(*modifier, env.current().octave),
-                        volume: env.current().volume as f32,
-                        waveform: env.current().waveform,
+impl EffectParameter {
+    /// 設定できる値の上限
+    pub const fn max(self) -> u32 {
+        match self {
+            Self::DelayTime => MAX_DELAY_MS,
+            Self::DelayFeedback => MAX_FEEDBACK,
+            Self::ChorusRate => MAX_CHORUS_RATE,
+            Self::ChorusDepth => MAX_CHORUS_DEPTH_MS,
+            Self::FilterCutoff => MAX_CUTOFF_HZ,
+            Self::FilterEnvelopeDepth => MAX_FILTER_DEPTH,
+            Self::VibratoDepth => MAX_VIBRATO_DEPTH,
+            Self::VibratoRate => MAX_VIBRATO_RATE,
+            Self::DelayMix
+            | Self::ReverbRoom
+            | Self::ReverbDamp
+            | Self::ReverbMix
+            | Self::ChorusMix
+            | Self::FilterResonance => 100,
+        }
+    }
+}
+
+impl Display for EffectParameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::DelayTime => "ディレイの時間",
+            Self::DelayFeedback => "ディレイのフィードバック",
+            Self::DelayMix => "ディレイの音量",
+            Self::ReverbRoom => "リバーブの部屋の大きさ",
+            Self::ReverbDamp => "リバーブの高音の減衰",
+            Self::ReverbMix => "リバーブの割合",
+            Self::ChorusRate => "コーラスの速さ",
+            Self::ChorusDepth => "コーラスの深さ",
+            Self::ChorusMix => "コーラスの割合",
+            Self::FilterCutoff => "カットオフ周波数",
+            Self::FilterResonance => "レゾナンス",
+            Self::FilterEnvelopeDepth => "フィルターエンベロープの深さ",
+            Self::VibratoDepth => "ビブラートの深さ",
+            Self::VibratoRate => "ビブラートの速さ",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 評価中に発生するエラー
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EvalError {
+    /// チャンネル番号が0～15の範囲外
+    ChannelOutOfRange(u32),
+    /// テンポが0
+    ZeroTempo,
+    /// 音符・休符・`L`コマンドの長さが0
+    ZeroLength,
+    /// ノート番号が0～127の範囲外
+    NoteNumberOutOfRange(i64),
+    /// `&`や`^`の前に音符・休符が無い
+    NothingToTie,
+    /// `~`の前に高さのある音符が無い
+    NothingToSlide,
+    /// `&`の後に音符・休符が無い
+    NothingAfterTie,
+    /// `~`の後に音符が無い
+    NothingAfterSlide,
+    /// 定義されていないマクロを展開しようとした
+    UndefinedMacro(String),
+    /// マクロが自分自身を展開しようとした
+    RecursiveMacro(String),
+    /// ループやマクロの展開が大きすぎる
+    TooManySteps,
+    /// `#include`が読み込まれていない
+    UnresolvedInclude(String),
+    /// ゲートタイムが1～8の範囲外
+    GateOutOfRange(u32),
+    /// 定位が0～127の範囲外
+    PanOutOfRange(u32),
+    /// エフェクトやフィルターの設定値が範囲外
+    EffectOutOfRange {
+        parameter: EffectParameter,
+        value: u32,
+    },
+    /// 定義されていないエンベロープを選択しようとした
+    UndefinedEnvelope(u32),
+    /// 組み込みのエンベロープを定義し直そうとした
+    ReservedEnvelope(u32),
+    /// サステインレベルが0～100の範囲外
+    SustainOutOfRange(u32),
+    /// 定義されていない音色を選択しようとした
+    UndefinedInstrument(u32),
+    /// 組み込みの音色の番号に音色を定義しようとした
+    ReservedInstrument(u32),
+    /// `@s`コマンドの音声ファイルが読み込まれていない
+    UnresolvedSample(String),
+    /// 音声ファイルのループの範囲が正しくない
+    SampleLoopOutOfRange { start: u32, end: u32, frames: usize },
+    /// FM音源のオペレーターの数・アルゴリズム・フィードバックの組み合わせが正しくない
+    InvalidFmVoice {
+        operators: usize,
+        algorithm: u32,
+        feedback: u32,
+    },
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ChannelOutOfRange(n) => {
+                write!(f, "チャンネル番号が大きすぎます: {} (0～15)", n)
+            }
+            Self::ZeroTempo => write!(f, "テンポに0は指定できません"),
+            Self::ZeroLength => write!(f, "音の長さに0は指定できません"),
+            Self::NoteNumberOutOfRange(n) => {
+                write!(f, "ノート番号が範囲外です: {} (0～127)", n)
+            }
+            Self::NothingToTie => write!(f, "タイ・スラーの前に音符がありません"),
+            Self::NothingToSlide => write!(f, "ポルタメントの前に高さのある音符がありません"),
+            Self::NothingAfterTie => write!(f, "タイ・スラーの後に音符がありません"),
+            Self::NothingAfterSlide => write!(f, "ポルタメントの後に音符がありません"),
+            Self::UndefinedMacro(name) => write!(f, "マクロ!{}は定義されていません", name),
+            Self::TooManySteps => write!(
+                f,
+                "ループやマクロの展開が大きすぎます (評価できるコマンドは{}個まで)",
+                MAX_EVAL_STEPS
+            ),
+            Self::RecursiveMacro(name) => {
+                write!(f, "マクロ!{}の展開が循環しています", name)
+            }
+            Self::GateOutOfRange(q) => write!(f, "ゲートタイムが範囲外です: {} (1～8)", q),
+            Self::PanOutOfRange(p) => write!(f, "定位が範囲外です: {} (0～127)", p),
+            Self::EffectOutOfRange { parameter, value } => write!(
+                f,
+                "{}が範囲外です: {} (0～{})",
+                parameter,
+                value,
+                parameter.max()
+            ),
+            Self::UndefinedEnvelope(n) => write!(f, "エンベロープ{}は定義されていません", n),
+            Self::ReservedEnvelope(n) => {
+                write!(f, "エンベロープ{}は組み込みのため定義できません", n)
+            }
+            Self::SustainOutOfRange(s) => {
+                write!(f, "サステインレベルが範囲外です: {} (0～100)", s)
+            }
+            Self::UndefinedInstrument(n) => write!(f, "音色{}は定義されていません", n),
+            Self::ReservedInstrument(n) => write!(
+                f,
+                "音色{}は組み込みのため定義できません (0～{})",
+                n,
+                RESERVED_INSTRUMENTS - 1
+            ),
+            Self::UnresolvedSample(path) => {
+                write!(f, "音声ファイル\"{}\"が読み込まれていません", path)
+            }
+            Self::SampleLoopOutOfRange { start, end, frames } => write!(
+                f,
+                "ループの範囲が正しくありません: {}～{} (音声ファイルは{}フレーム)",
+                start, end, frames
+            ),
+            Self::InvalidFmVoice {
+                operators,
+                algorithm,
+                feedback,
+            } => write!(
+                f,
+                "FM音源の音色が正しくありません: オペレーター{}個、アルゴリズム{}、フィードバック{} (2オペレーターではアルゴリズム0～1、4オペレーターでは0～7、フィードバックは0～7)",
+                operators, algorithm, feedback
+            ),
+            Self::UnresolvedInclude(path) => {
+                write!(f, "ファイル\"{}\"が読み込まれていません", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl<T: Display> Display for Spanned<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.node)
+    }
+}
+
+impl<T: std::error::Error> std::error::Error for Spanned<T> {}
+
+/// コマンドを1つずつ評価してタイムラインを組み立てる
+struct Evaluator<'a> {
+    env: &'a mut EvalEnv,
+    elements: [Vec<Element>; 16],
+    /// `&`の後で次の音を待っているチャンネル
+    pending_ties: [bool; 16],
+    /// `~`の後で次の音を待っているチャンネル
+    pending_slides: [bool; 16],
+    /// 各チャンネルで最後に評価した`&`や`~`の位置
+    pending_spans: [Option<Span>; 16],
+    /// 展開中のマクロ
+    macro_stack: Vec<String>,
+    /// マクロの本体の各コマンドの位置
+    macro_spans: BTreeMap<String, Vec<CommandSpan>>,
+    /// エラーが発生したループやマクロの本体のコマンドの位置
+    error_span: Option<Span>,
+    /// 評価中のコマンドの位置。位置の無いASTを評価するときは`None`
+    span: Option<Span>,
+    /// 評価したコマンドとループの繰り返しの数
+    steps: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(env: &'a mut EvalEnv) -> Self {
+        Self {
+            env,
+            elements: Default::default(),
+            pending_ties: [false; 16],
+            pending_slides: [false; 16],
+            pending_spans: [None; 16],
+            macro_stack: Vec::new(),
+            macro_spans: BTreeMap::new(),
+            error_span: None,
+            span: None,
+            steps: 0,
+        }
+    }
+
+    /// `&`や`~`の後に音が続いていないチャンネルがあればエラーにする
+    ///
+    /// エラーの場合、その`&`や`~`の位置を`error_span`に残す。
+    fn check_pending(&mut self) -> Result<(), EvalError> {
+        for channel in 0..16 {
+            let error = if self.pending_ties[channel] {
+                EvalError::NothingAfterTie
+            } else if self.pending_slides[channel] {
+                EvalError::NothingAfterSlide
+            } else {
+                continue;
+            };
+            self.error_span = self.pending_spans[channel];
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> LmmlTimeline {
+        LmmlTimeline {
+            timeline: self.elements,
+        }
+    }
+
+    fn push(&mut self, element: Element) {
+        self.elements[self.env.current_channel].push(element);
+    }
+
+    /// 現在のチャンネルの最後の音
+    fn last_note_mut(&mut self) -> Option<&mut Note> {
+        self.elements[self.env.current_channel]
+            .iter_mut()
+            .rev()
+            .find_map(|e| match e {
+                Element::Note(note) => Some(note),
+                Element::Event(_) => None,
+            })
+    }
+
+    /// 現在のチャンネルで選択されているエンベロープ
+    fn envelope(&self) -> Envelope {
+        let number = self.env.current().envelope;
+        self.env.envelopes.get(&number).copied().unwrap_or_default()
+    }
+
+    /// 現在のチャンネルの音色でノート番号`notenumbers`の音を鳴らす音
+    fn tone(&self, notenumbers: &[i32], is_chord: bool) -> NoteType {
+        let current = self.env.current();
+        let volume = current.volume as f32;
+        let envelope = self.envelope();
+        let vibrato = current.vibrato;
+        if current.waveform == PERCUSSION {
+            let mut drums: Vec<Drum> = Vec::with_capacity(notenumbers.len());
+            for drum in notenumbers.iter().map(|n| Drum::from_notenumber(*n)) {
+                if !drums.contains(&drum) {
+                    drums.push(drum);
+                }
+            }
+            return NoteType::Drum {
+                drums,
+                volume,
+                envelope,
+            };
+        }
+        let waveform = current.waveform;
+        let instrument = self.env.instruments.get(&waveform).cloned();
+        if is_chord {
+            NoteType::Chord {
+                hzs: notenumbers.iter().map(|n| notenumber_to_hz(*n)).collect(),
+                volume,
+                waveform,
+                instrument,
+                envelope,
+                vibrato,
+            }
+        } else {
+            NoteType::Single {
+                hz: notenumber_to_hz(notenumbers[0]),
+                volume,
+                waveform,
+                instrument,
+                envelope,
+                vibrato,
+                slide: None,
+            }
+        }
+    }
+
+    /// 長さ`length_ms`のうちゲートタイムの割合だけ鳴る時間
+    const fn sound_ms(&self, length_ms: u32) -> u32 {
+        (length_ms as u64 * self.env.current().gate as u64 / 8) as u32
+    }
+
+    /// 音を追加する
+    ///
+    /// 直前に`&`があり、直前の音と同じ高さの音であれば直前の音を延ばす。
+    /// 異なる高さの音であれば直前の音をゲートタイムによらず最後まで鳴らす。
+    fn push_note(&mut self, length_ms: u32, mut note_type: NoteType) {
+        let sound_ms = self.sound_ms(length_ms);
+        let tied = std::mem::take(&mut self.pending_ties[self.env.current_channel]);
+        let slid = std::mem::take(&mut self.pending_slides[self.env.current_channel]);
+        if tied && let Some(last) = self.last_note_mut() {
+            // 直前の音がポルタメントで変わる音でも、同じ高さならつなげる
+            if without_slide(&last.note_type) == note_type {
+                last.sound_ms = last.length_ms.saturating_add(sound_ms);
+                last.length_ms = last.length_ms.saturating_add(length_ms);
+                return;
+            }
+            last.sound_ms = last.length_ms;
+        }
+        self.set_slide(&mut note_type, slid, sound_ms);
+        self.push(Element::Note(Note {
+            length_ms,
+            sound_ms,
+            note_type,
+        }));
+    }
+
+    /// 現在のチャンネルの最後の音の高さ。和音なら最も高い音。
+    fn last_hz(&self) -> Option<f32> {
+        let last = self.elements[self.env.current_channel]
+            .iter()
+            .rev()
+            .find_map(|e| match e {
+                Element::Note(note) if note.note_type != NoteType::Rest => Some(note),
+                _ => None,
+            })?;
+        match last.note_type {
+            NoteType::Single { hz, .. } => Some(hz),
+            NoteType::Chord { ref hzs, .. } => hzs.last().copied(),
+            NoteType::Drum { .. } | NoteType::Rest => None,
+        }
+    }
+
+    /// `~`の後の音やポルタメントの時間が設定されている場合に、
+    /// 単音の高さを直前の音の高さから滑らかに変える
+    ///
+    /// FM音源や音声ファイルの音色は高さを変えられないため、何もしない。
+    fn set_slide(&self, note_type: &mut NoteType, slid: bool, sound_ms: u32) {
+        let glide_ms = self.env.current().glide_ms;
+        if !slid && glide_ms == 0 {
+            return;
+        }
+        let NoteType::Single {
+            hz,
+            instrument,
+            slide,
+            ..
+        } = note_type
+        else {
+            return;
+        };
+        if let Some(Instrument::Fm(_) | Instrument::Sample(_)) = instrument.as_deref() {
+            return;
+        }
+        let Some(from_hz) = self.last_hz() else {
+            return;
+        };
+        if from_hz != *hz {
+            *slide = Some(Slide {
+                from_hz,
+                time_ms: if glide_ms == 0 { sound_ms } else { glide_ms },
+            });
+        }
+    }
+
+    /// 音符・休符の長さをミリ秒単位で求める
+    fn length_ms(&self, length: Option<u32>, is_dotted: bool) -> Result<u32, EvalError> {
+        let current = self.env.current();
+        let length = resolve_length(current.length, current.is_dotted, length, is_dotted);
+        length_to_ms(current.tempo, length)
+    }
+
+    /// 評価の手数を数え、多すぎる場合はエラーにする
+    const fn step(&mut self) -> Result<(), EvalError> {
+        self.steps += 1;
+        if self.steps > MAX_EVAL_STEPS {
+            return Err(EvalError::TooManySteps);
+        }
+        Ok(())
+    }
+
+    /// ループやマクロの本体のコマンドを評価する
+    ///
+    /// エラーが発生した場合、最も内側のコマンドの位置を`error_span`に残す。
+    fn body_command(
+        &mut self,
+        command: &LmmlCommand,
+        span: Option<&CommandSpan>,
+    ) -> Result<(), EvalError> {
+        let body = span.map_or(&[][..], |s| s.body.as_slice());
+        if let Some(span) = span {
+            self.span = Some(span.span);
+        }
+        let result = self.command(command, body);
+        if result.is_err() && self.error_span.is_none() {
+            self.error_span = span.map(|s| s.span);
+        }
+        result
+    }
+
+    /// コマンドを評価する。`body`はループやマクロの定義の本体の各コマンドの位置。
+    fn command(&mut self, command: &LmmlCommand, body: &[CommandSpan]) -> Result<(), EvalError> {
+        self.step()?;
+        match command {
+            LmmlCommand::Note {
+                note,
+                modifier,
+                length: l,
+                is_dotted,
+            } => {
+                let length_ms = self.length_ms(*l, *is_dotted)?;
+                let notenumber =
+                    check_notenumber(note.to_notenumber(*modifier, self.env.current().octave))?;
+                self.push_note(length_ms, self.tone(&[notenumber], false));
+            }
+            LmmlCommand::Rest {
+                length: l,
+                is_dotted,
+            } => {
+                let length_ms = self.length_ms(*l, *is_dotted)?;
+                self.push_note(length_ms, NoteType::Rest);
+            }
+            LmmlCommand::Chord {
+                notes,
+                length: l,
+                is_dotted,
+            } => {
+                let length_ms = self.length_ms(*l, *is_dotted)?;
+                let mut notenumbers = notes
+                    .iter()
+                    .map(|(n, m)| n.to_notenumber(*m, self.env.current().octave))
+                    .collect::<Vec<_>>();
+                check_notenumber(notenumbers[0])?;
+                for i in 1..notenumbers.len() {
+                    while notenumbers[i - 1] >= notenumbers[i] {
+                        notenumbers[i] += 12;
+                    }
+                    check_notenumber(notenumbers[i])?;
+                }
+                self.push_note(length_ms, self.tone(&notenumbers, true));
+            }
+            LmmlCommand::NoteNumber(n) => {
+                if *n > 127 {
+                    return Err(EvalError::NoteNumberOutOfRange(*n as i64));
+                }
+                let length_ms = self.length_ms(None, false)?;
+                self.push_note(length_ms, self.tone(&[*n as i32], false));
+            }
+            LmmlCommand::SetOctave(o) => {
+                self.env.current_mut().octave = i32::try_from(*o).unwrap_or(i32::MAX)
+            }
+            LmmlCommand::SetLength(l, d) => {
+                if *l == 0 {
+                    return Err(EvalError::ZeroLength);
+                }
+                self.env.current_mut().length = *l;
+                self.env.current_mut().is_dotted = *d;
+            }
+            LmmlCommand::SetVolume(v) => self.env.current_mut().volume = *v,
+            LmmlCommand::SetPan(p) => {
+                if *p > 127 {
+                    return Err(EvalError::PanOutOfRange(*p));
+                }
+                self.env.current_mut().pan = *p;
+                self.push(Element::Event(Event::ChangePan(*p)));
+            }
+            LmmlCommand::SetDelay(delay) => {
+                check_effect(EffectParameter::DelayTime, delay.time_ms)?;
+                check_effect(EffectParameter::DelayFeedback, delay.feedback)?;
+                check_effect(EffectParameter::DelayMix, delay.mix)?;
+                self.env.current_mut().effects.delay = *delay;
+                self.push(Element::Event(Event::ChangeDelay(*delay)));
+            }
+            LmmlCommand::SetReverb(reverb) => {
+                check_effect(EffectParameter::ReverbRoom, reverb.room)?;
+                check_effect(EffectParameter::ReverbDamp, reverb.damp)?;
+                check_effect(EffectParameter::ReverbMix, reverb.mix)?;
+                self.env.current_mut().effects.reverb = *reverb;
+                self.push(Element::Event(Event::ChangeReverb(*reverb)));
+            }
+            LmmlCommand::SetChorus(chorus) => {
+                check_effect(EffectParameter::ChorusRate, chorus.rate)?;
+                check_effect(EffectParameter::ChorusDepth, chorus.depth_ms)?;
+                check_effect(EffectParameter::ChorusMix, chorus.mix)?;
+                self.env.current_mut().effects.chorus = *chorus;
+                self.push(Element::Event(Event::ChangeChorus(*chorus)));
+            }
+            LmmlCommand::SetFilter(filter) => {
+                check_effect(EffectParameter::FilterCutoff, filter.cutoff_hz)?;
+                check_effect(EffectParameter::FilterResonance, filter.resonance)?;
+                self.env.current_mut().filter = *filter;
+                self.push(Element::Event(Event::ChangeFilter(*filter)));
+            }
+            LmmlCommand::SetFilterEnvelope { envelope, depth } => {
+                if *envelope != 0 && !self.env.envelopes.contains_key(envelope) {
+                    return Err(EvalError::UndefinedEnvelope(*envelope));
+                }
+                check_effect(EffectParameter::FilterEnvelopeDepth, *depth)?;
+                let filter_envelope = FilterEnvelope {
+                    envelope: self
+                        .env
+                        .envelopes
+                        .get(envelope)
+                        .copied()
+                        .unwrap_or_default(),
+                    depth: *depth,
+                };
+                self.env.current_mut().filter_envelope = filter_envelope;
+                self.push(Element::Event(Event::ChangeFilterEnvelope(filter_envelope)));
+            }
+            LmmlCommand::SetVibrato(vibrato) => {
+                check_effect(EffectParameter::VibratoDepth, vibrato.depth)?;
+                check_effect(EffectParameter::VibratoRate, vibrato.rate)?;
+                self.env.current_mut().vibrato = *vibrato;
+            }
+            LmmlCommand::SetGlide(ms) => self.env.current_mut().glide_ms = *ms,
+            LmmlCommand::SetGate(q) => {
+                if !(1..=8).contains(q) {
+                    return Err(EvalError::GateOutOfRange(*q));
+                }
+                self.env.current_mut().gate = *q;
+            }
+            LmmlCommand::DefineEnvelope {
+                number,
+                attack_ms,
+                decay_ms,
+                sustain,
+                release_ms,
+            } => {
+                if *number == 0 {
+                    return Err(EvalError::ReservedEnvelope(*number));
+                }
+                if *sustain > 100 {
+                    return Err(EvalError::SustainOutOfRange(*sustain));
+                }
+                self.env.envelopes.insert(
+                    *number,
+                    Envelope::Adsr {
+                        attack_ms: *attack_ms,
+                        decay_ms: *decay_ms,
+                        sustain: *sustain,
+                        release_ms: *release_ms,
                     },
-                    length_ms: length_to_ms(
-                        env.current().tempo,
-                        resolve_length(
-                            env.current().length,
-                            env.current().is_dotted,
-                            *l,
-                            *is_dotted,
-                        ),
-                    ),
-                })),
-                LmmlCommand::Rest {
-                    length: l,
-                    is_dotted,
-                } => elements[env.current_channel].push(Element::Note(Note {
-                    note_type: NoteType::Rest,
-                    length_ms: length_to_ms(
-                        env.current().tempo,
-                        resolve_length(
-                            env.current().length,
-                            env.current().is_dotted,
-                            *l,
-                            *is_dotted,
-                        ),
-                    ),
-                })),
-                LmmlCommand::Chord {
-                    notes,
-                    length: l,
-                    is_dotted,
-                } => {
-                    let mut notenumbers = notes
-                        .iter()
-                        .map(|(n, m)| n.to_notenumber(*m, env.current().octave))
-                        .collect::<Vec<_>>();
-                    for i in 1..notenumbers.len() {
-                        while notenumbers[i - 1] >= notenumbers[i] {
-                            notenumbers[i] += 12;
-                        }
+                );
+            }
+            LmmlCommand::SetEnvelope(n) => {
+                if *n != 0 && !self.env.envelopes.contains_key(n) {
+                    return Err(EvalError::UndefinedEnvelope(*n));
+                }
+                self.env.current_mut().envelope = *n;
+            }
+            LmmlCommand::SetTempo(t) => {
+                if *t == 0 {
+                    return Err(EvalError::ZeroTempo);
+                }
+                self.env.current_mut().tempo = *t;
+                self.push(Element::Event(Event::ChangeTempo(*t)));
+            }
+            LmmlCommand::DefineWavetable { number, samples } => {
+                if *number < RESERVED_INSTRUMENTS {
+                    return Err(EvalError::ReservedInstrument(*number));
+                }
+                self.env
+                    .instruments
+                    .insert(*number, Arc::new(Instrument::wavetable(samples)));
+            }
+            LmmlCommand::DefineFm {
+                number,
+                algorithm,
+                feedback,
+                operators,
+            } => {
+                if *number < RESERVED_INSTRUMENTS {
+                    return Err(EvalError::ReservedInstrument(*number));
+                }
+                let mut fm_operators = Vec::with_capacity(operators.len());
+                for &[ratio, level, attack_ms, decay_ms, sustain, release_ms] in operators {
+                    if sustain > 100 {
+                        return Err(EvalError::SustainOutOfRange(sustain));
                     }
-                    let hzs = notenumbers.iter().map(|n| notenumber_to_hz(*n)).collect();
-                    elements[env.current_channel].push(Element::Note(Note {
-                        note_type: NoteType::Chord {
-                            hzs,
-                            volume: env.current().volume as f32,
-                            waveform: env.current().waveform,
+                    fm_operators.push(FmOperator {
+                        ratio,
+                        level,
+                        envelope: Envelope::Adsr {
+                            attack_ms,
+                            decay_ms,
+                            sustain,
+                            release_ms,
                         },
-                        length_ms: length_to_ms(
-                            env.current().tempo,
-                            resolve_length(
-                                env.current().length,
-                                env.current().is_dotted,
-                                *l,
-                                *is_dotted,
-                            ),
-                        ),
-                    }))
-                }
-                LmmlCommand::NoteNumber(n) => {
-                    elements[env.current_channel].push(Element::Note(Note {
-                        note_type: NoteType::Single {
-                            hz: notenumber_to_hz(*n as i32),
-                            volume: env.current().volume as f32,
-                            waveform: env.current().waveform,
-                        },
-                        length_ms: length_to_ms(
-                            env.current().tempo,
-                            (env.current().length, env.current().is_dotted),
-                        ),
-                    }));
-                }
-                LmmlCommand::SetOctave(o) => env.current_mut().octave = *o as i32,
-                LmmlCommand::SetLength(l, d) => {
-                    env.current_mut().length = *l;
-                    env.current_mut().is_dotted = *d;
-                }
-                LmmlCommand::SetVolume(v) => env.current_mut().volume = *v,
-                LmmlCommand::SetTempo(t) => {
-                    env.current_mut().tempo = *t;
-                    elements[env.current_channel].push(Element::Event(Event::ChangeTempo(*t)));
-                }
-                LmmlCommand::SetWaveform(n) => env.current_mut().waveform = *n,
-                LmmlCommand::SetChannel(n) => {
-                    if *n > 15 {
-                        panic!("チャンネル番号が大きすぎます : {}", n);
-                    } else {
-                        env.current_channel = *n as usize;
+                    });
+                }
+                let voice = FmVoice {
+                    algorithm: *algorithm,
+                    feedback: *feedback,
+                    operators: fm_operators,
+                };
+                if !voice.is_valid() {
+                    return Err(EvalError::InvalidFmVoice {
+                        operators: operators.len(),
+                        algorithm: *algorithm,
+                        feedback: *feedback,
+                    });
+                }
+                self.env
+                    .instruments
+                    .insert(*number, Arc::new(Instrument::Fm(voice)));
+            }
+            LmmlCommand::DefineSample {
+                number,
+                path,
+                root,
+                loop_frames,
+            } => {
+                if *number < RESERVED_INSTRUMENTS {
+                    return Err(EvalError::ReservedInstrument(*number));
+                }
+                if *root > 127 {
+                    return Err(EvalError::NoteNumberOutOfRange(*root as i64));
+                }
+                let Some(data) = self.env.samples.get(path) else {
+                    return Err(EvalError::UnresolvedSample(path.clone()));
+                };
+                if let Some((start, end)) = *loop_frames
+                    && (start >= end || end as usize > data.frames.len())
+                {
+                    return Err(EvalError::SampleLoopOutOfRange {
+                        start,
+                        end,
+                        frames: data.frames.len(),
+                    });
+                }
+                let instrument = Instrument::Sample(SampleInstrument {
+                    data: data.clone(),
+                    root: *root,
+                    loop_frames: *loop_frames,
+                });
+                self.env.instruments.insert(*number, Arc::new(instrument));
+            }
+            LmmlCommand::SetWaveform(n) => {
+                if *n >= RESERVED_INSTRUMENTS && !self.env.instruments.contains_key(n) {
+                    return Err(EvalError::UndefinedInstrument(*n));
+                }
+                self.env.current_mut().waveform = *n;
+            }
+            LmmlCommand::SetChannel(n) => {
+                if *n > 15 {
+                    return Err(EvalError::ChannelOutOfRange(*n));
+                }
+                self.env.current_channel = *n as usize;
+            }
+            LmmlCommand::Slide => {
+                if self.last_hz().is_none() {
+                    return Err(EvalError::NothingToSlide);
+                }
+                self.pending_slides[self.env.current_channel] = true;
+                self.pending_spans[self.env.current_channel] = self.span;
+            }
+            LmmlCommand::Tie => {
+                if self.last_note_mut().is_none() {
+                    return Err(EvalError::NothingToTie);
+                }
+                self.pending_ties[self.env.current_channel] = true;
+                self.pending_spans[self.env.current_channel] = self.span;
+            }
+            LmmlCommand::TieLength {
+                length: l,
+                is_dotted,
+            } => {
+                let length_ms = self.length_ms(*l, *is_dotted)?;
+                let sound_ms = self.sound_ms(length_ms);
+                let last = self.last_note_mut().ok_or(EvalError::NothingToTie)?;
+                last.sound_ms = last.length_ms.saturating_add(sound_ms);
+                last.length_ms = last.length_ms.saturating_add(length_ms);
+            }
+            LmmlCommand::IncreaseOctave => {
+                let octave = &mut self.env.current_mut().octave;
+                *octave = octave.saturating_add(1);
+            }
+            LmmlCommand::DecreaseOctave => {
+                let octave = &mut self.env.current_mut().octave;
+                *octave = octave.saturating_sub(1);
+            }
+            LmmlCommand::Loop {
+                body: commands,
+                count,
+            } => {
+                let count = count.unwrap_or(2);
+                for i in 0..count {
+                    self.step()?;
+                    let is_last = i + 1 == count;
+                    for (j, command) in commands.iter().enumerate() {
+                        if *command == LmmlCommand::LoopBreak && is_last {
+                            break;
+                        }
+                        self.body_command(command, body.get(j))?;
                     }
                 }
-                LmmlCommand::IncreaseOctave => env.current_mut().octave += 1,
-                LmmlCommand::DecreaseOctave => env.current_mut().octave -= 1,
+            }
+            LmmlCommand::LoopBreak => {}
+            LmmlCommand::DefineMacro {
+                name,
+                body: commands,
+            } => {
+                self.env.macros.insert(name.clone(), commands.clone());
+                self.macro_spans.insert(name.clone(), body.to_vec());
+            }
+            LmmlCommand::CallMacro(name) => {
+                if self.macro_stack.contains(name) {
+                    return Err(EvalError::RecursiveMacro(name.clone()));
+                }
+                let body = self
+                    .env
+                    .macros
+                    .get(name)
+                    .ok_or_else(|| EvalError::UndefinedMacro(name.clone()))?
+                    .clone();
+                let spans = self.macro_spans.get(name).cloned().unwrap_or_default();
+                self.macro_stack.push(name.clone());
+                for (i, command) in body.iter().enumerate() {
+                    self.body_command(command, spans.get(i))?;
+                }
+                self.macro_stack.pop();
+            }
+            LmmlCommand::Include(path) => {
+                return Err(EvalError::UnresolvedInclude(path.clone()));
             }
         }
+        Ok(())
+    }
+}
 
-        LmmlTimeline { timeline: elements }
+impl LmmlAst {
+    pub fn to_timeline(&self, env: &mut EvalEnv) -> Result<LmmlTimeline, EvalError> {
+        let mut evaluator = Evaluator::new(env);
+        for command in self.0.iter() {
+            evaluator.command(command, &[])?;
+        }
+        evaluator.check_pending()?;
+        Ok(evaluator.finish())
+    }
+}
+
+impl SpannedLmmlAst {
+    /// [`LmmlAst::to_timeline`]と同様に評価する。エラーには原因となったコマンドの位置が付く。
+    ///
+    /// ループやマクロの本体でエラーが発生した場合は、本体の中のコマンドの位置が付く。
+    pub fn to_timeline(&self, env: &mut EvalEnv) -> Result<LmmlTimeline, Spanned<EvalError>> {
+        let mut evaluator = Evaluator::new(env);
+        for command in self.0.iter() {
+            evaluator.span = Some(command.span);
+            evaluator
+                .command(&command.node, &command.body)
+                .map_err(|node| Spanned {
+                    span: evaluator.error_span.unwrap_or(command.span),
+                    node,
+                })?;
+        }
+        // 音を待っている`&`や`~`があれば、コマンドが1つ以上あり`error_span`にその位置が残る
+        if let (Err(node), Some(last)) = (evaluator.check_pending(), self.0.last()) {
+            return Err(Spanned {
+                span: evaluator.error_span.unwrap_or(last.span),
+                node,
+            });
+        }
+        Ok(evaluator.finish())
     }
 }
 
@@ -286,7 +1450,10 @@ impl NoteChar {
             NoteModifier::Flat => -1,
             NoteModifier::Natural => 0,
         };
-        base + modifier + ((octave + 1) * 12)
+        octave
+            .saturating_add(1)
+            .saturating_mul(12)
+            .saturating_add(base + modifier)
     }
 }
 
@@ -298,6 +1465,41 @@ pub fn notenumber_to_hz(notenumber: i32) -> f32 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn eval_error() {
+        let eval = |commands| LmmlAst(commands).to_timeline(&mut EvalEnv::default());
+        assert_eq!(
+            eval(vec![LmmlCommand::SetChannel(16)]),
+            Err(EvalError::ChannelOutOfRange(16))
+        );
+        assert_eq!(
+            eval(vec![LmmlCommand::SetTempo(0)]),
+            Err(EvalError::ZeroTempo)
+        );
+        assert_eq!(
+            eval(vec![LmmlCommand::SetLength(0, false)]),
+            Err(EvalError::ZeroLength)
+        );
+        assert_eq!(
+            eval(vec![LmmlCommand::Rest {
+                length: Some(0),
+                is_dotted: true
+            }]),
+            Err(EvalError::ZeroLength)
+        );
+        assert_eq!(
+            eval(vec![LmmlCommand::NoteNumber(128)]),
+            Err(EvalError::NoteNumberOutOfRange(128))
+        );
+        assert!(
+            eval(vec![
+                LmmlCommand::SetChannel(15),
+                LmmlCommand::NoteNumber(127)
+            ])
+            .is_ok()
+        );
+    }
+
     #[test]
     fn to_notenumber() {
         assert_eq!(NoteChar::C.to_notenumber(NoteModifier::Natural, 4), 60);
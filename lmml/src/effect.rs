@@ -0,0 +1,385 @@
+use crate::oscillator::SAMPLE_RATE;
+
+/// ディレイの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Delay {
+    /// やまびこが返ってくるまでの時間(ミリ秒)
+    pub time_ms: u32,
+    /// やまびこが繰り返されるときの音量(百分率)
+    pub feedback: u32,
+    /// 元の音に加えるやまびこの音量(百分率)。0なら無効。
+    pub mix: u32,
+}
+
+/// リバーブの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Reverb {
+    /// 部屋の大きさ(百分率)。大きいほど残響が長くなる。
+    pub room: u32,
+    /// 高音の減衰の強さ(百分率)
+    pub damp: u32,
+    /// 残響の割合(百分率)。0なら無効。
+    pub mix: u32,
+}
+
+/// コーラスの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Chorus {
+    /// 揺れの速さ(0.1Hz単位)
+    pub rate: u32,
+    /// 揺れの深さ(ミリ秒)
+    pub depth_ms: u32,
+    /// 揺らした音の割合(百分率)。0なら無効。
+    pub mix: u32,
+}
+
+/// チャンネルごとのエフェクトの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Effects {
+    pub delay: Delay,
+    pub reverb: Reverb,
+    pub chorus: Chorus,
+}
+
+/// ディレイの時間の上限(ミリ秒)
+pub const MAX_DELAY_MS: u32 = 2000;
+/// ディレイのフィードバックの上限(百分率)。100以上では音が減衰しなくなる。
+pub const MAX_FEEDBACK: u32 = 95;
+/// コーラスの揺れの速さの上限(0.1Hz単位)
+pub const MAX_CHORUS_RATE: u32 = 200;
+/// コーラスの深さの上限(ミリ秒)
+pub const MAX_CHORUS_DEPTH_MS: u32 = 20;
+
+/// コーラスで揺らす遅延時間の中心(ミリ秒)
+const CHORUS_BASE_MS: u32 = 20;
+
+/// 音が聞こえなくなったとみなす減衰の割合
+const SILENCE: f64 = 1e-3;
+
+const fn ms_to_frames(ms: u32) -> usize {
+    (ms as u64 * SAMPLE_RATE as u64 / 1000) as usize
+}
+
+/// 一定の長さの遅延線
+#[derive(Debug, Clone)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len.max(1)],
+            position: 0,
+        }
+    }
+
+    /// `delay`フレーム前の値(1～バッファの長さ)
+    fn read(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.position + len - delay.clamp(1, len)) % len]
+    }
+
+    /// `delay`フレーム前の値を、間のフレームを線形補間して読む
+    fn read_fractional(&self, delay: f64) -> f32 {
+        let whole = delay.floor();
+        let a = self.read(whole as usize);
+        let b = self.read(whole as usize + 1);
+        (b - a).mul_add((delay - whole) as f32, a)
+    }
+
+    fn write(&mut self, value: f32) {
+        self.buffer[self.position] = value;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+}
+
+/// Freeverbのコムフィルター
+#[derive(Debug, Clone)]
+struct Comb {
+    line: DelayLine,
+    len: usize,
+    filter: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.line.read(self.len);
+        self.filter = (self.filter - output).mul_add(damp, output);
+        self.line.write(self.filter.mul_add(feedback, input));
+        output
+    }
+}
+
+/// Freeverbのオールパスフィルター
+#[derive(Debug, Clone)]
+struct Allpass {
+    line: DelayLine,
+    len: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line.read(self.len);
+        self.line.write(delayed.mul_add(0.5, input));
+        delayed - input
+    }
+}
+
+/// 44.1kHzでのFreeverbのコムフィルターの長さ
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+/// 44.1kHzでのFreeverbのオールパスフィルターの長さ
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+
+/// Schroeder型のリバーブ(Freeverb)
+#[derive(Debug, Clone)]
+struct ReverbState {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl ReverbState {
+    fn new() -> Self {
+        Self {
+            combs: COMB_LENGTHS
+                .iter()
+                .map(|len| Comb {
+                    line: DelayLine::new(*len),
+                    len: *len,
+                    filter: 0.0,
+                })
+                .collect(),
+            allpasses: ALLPASS_LENGTHS
+                .iter()
+                .map(|len| Allpass {
+                    line: DelayLine::new(*len),
+                    len: *len,
+                })
+                .collect(),
+        }
+    }
+
+    fn feedback(reverb: &Reverb) -> f32 {
+        0.28f32.mul_add(reverb.room.min(100) as f32 / 100.0, 0.7)
+    }
+
+    fn process(&mut self, input: f32, reverb: &Reverb) -> f32 {
+        let feedback = Self::feedback(reverb);
+        let damp = 0.4 * reverb.damp.min(100) as f32 / 100.0;
+        let input = input * 0.015;
+        let mut output: f32 = self
+            .combs
+            .iter_mut()
+            .map(|comb| comb.process(input, feedback, damp))
+            .sum();
+        for allpass in self.allpasses.iter_mut() {
+            output = allpass.process(output);
+        }
+        output * 3.0
+    }
+
+    /// 入力が無くなってから残響が消えるまでのフレーム数
+    fn tail(reverb: &Reverb) -> usize {
+        let feedback = Self::feedback(reverb) as f64;
+        let longest = COMB_LENGTHS[COMB_LENGTHS.len() - 1];
+        let repeats = SILENCE.log(feedback).ceil() as usize;
+        longest * repeats + ALLPASS_LENGTHS.iter().sum::<usize>()
+    }
+}
+
+/// チャンネルの音にエフェクトをかける
+///
+/// コーラス、ディレイ、リバーブの順にかける。
+/// 元の音が終わった後も、ディレイやリバーブの音が消えるまで出力を続ける。
+#[derive(Debug, Clone, Default)]
+pub struct EffectChain {
+    delay: Option<DelayLine>,
+    reverb: Option<ReverbState>,
+    chorus: Option<DelayLine>,
+    /// コーラスの揺れの位相(0～1)
+    chorus_phase: f64,
+    /// 元の音が終わった後に出力するフレーム数
+    tail: Option<usize>,
+}
+
+impl EffectChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 元の音の1フレームにエフェクトをかける。`input`が`None`なら元の音は終わっている。
+    pub fn process(&mut self, input: Option<f32>, effects: &Effects) -> Option<f32> {
+        let input = match input {
+            Some(input) => input,
+            None => {
+                let tail = self.tail.get_or_insert_with(|| Self::tail(effects));
+                *tail = tail.checked_sub(1)?;
+                0.0
+            }
+        };
+        let mut output = input;
+
+        let chorus = &effects.chorus;
+        if chorus.mix != 0 {
+            let line = self
+                .chorus
+                .get_or_insert_with(|| DelayLine::new(ms_to_frames(CHORUS_BASE_MS * 2 + 2)));
+            let depth = ms_to_frames(chorus.depth_ms.min(MAX_CHORUS_DEPTH_MS)) as f64;
+            let base = ms_to_frames(CHORUS_BASE_MS) as f64;
+            let swing = (2.0 * std::f64::consts::PI * self.chorus_phase).sin();
+            let wet = line.read_fractional(depth.mul_add(swing, base));
+            line.write(output);
+            self.chorus_phase =
+                (self.chorus_phase + chorus.rate as f64 / 10.0 / SAMPLE_RATE as f64).fract();
+            output = mix(output, wet, chorus.mix);
+        }
+
+        let delay = &effects.delay;
+        if delay.mix != 0 {
+            let line = self
+                .delay
+                .get_or_insert_with(|| DelayLine::new(ms_to_frames(MAX_DELAY_MS)));
+            let echo = line.read(ms_to_frames(delay.time_ms.min(MAX_DELAY_MS)));
+            let feedback = delay.feedback.min(MAX_FEEDBACK) as f32 / 100.0;
+            line.write(echo.mul_add(feedback, output));
+            output = echo.mul_add(delay.mix.min(100) as f32 / 100.0, output);
+        }
+
+        let reverb = &effects.reverb;
+        if reverb.mix != 0 {
+            let wet = self
+                .reverb
+                .get_or_insert_with(ReverbState::new)
+                .process(output, reverb);
+            output = mix(output, wet, reverb.mix);
+        }
+
+        Some(output)
+    }
+
+    /// 元の音が終わってからエフェクトの音が消えるまでのフレーム数
+    fn tail(effects: &Effects) -> usize {
+        let mut tail = 0;
+        let delay = &effects.delay;
+        if delay.mix != 0 {
+            let feedback = delay.feedback.min(MAX_FEEDBACK) as f64 / 100.0;
+            let repeats = if feedback == 0.0 {
+                1
+            } else {
+                SILENCE.log(feedback).ceil() as usize + 1
+            };
+            tail += ms_to_frames(delay.time_ms.min(MAX_DELAY_MS)) * repeats;
+        }
+        if effects.reverb.mix != 0 {
+            tail += ReverbState::tail(&effects.reverb);
+        }
+        if effects.chorus.mix != 0 {
+            tail += ms_to_frames(CHORUS_BASE_MS + MAX_CHORUS_DEPTH_MS);
+        }
+        tail
+    }
+}
+
+/// 元の音`dry`とエフェクトをかけた音`wet`を`mix`(百分率)の割合で混ぜる
+fn mix(dry: f32, wet: f32, mix: u32) -> f32 {
+    let mix = mix.min(100) as f32 / 100.0;
+    (wet - dry).mul_add(mix, dry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// インパルスにエフェクトをかけた結果
+    fn impulse_response(effects: &Effects) -> Vec<f32> {
+        let mut chain = EffectChain::new();
+        let mut input = std::iter::once(1.0).chain(std::iter::repeat_n(0.0, 99));
+        std::iter::from_fn(|| chain.process(input.next(), effects)).collect()
+    }
+
+    #[test]
+    fn bypass() {
+        let response = impulse_response(&Effects::default());
+        assert_eq!(response.len(), 100);
+        assert_eq!(response[0], 1.0);
+        assert!(response[1..].iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn delay() {
+        let effects = Effects {
+            delay: Delay {
+                time_ms: 10,
+                feedback: 50,
+                mix: 100,
+            },
+            ..Default::default()
+        };
+        let response = impulse_response(&effects);
+        // 10ms = 441フレーム
+        assert_eq!(response[0], 1.0);
+        assert_eq!(response[441], 1.0);
+        assert_eq!(response[882], 0.5);
+        assert_eq!(response[1323], 0.25);
+        assert!(
+            response
+                .iter()
+                .enumerate()
+                .all(|(i, s)| i % 441 == 0 || *s == 0.0)
+        );
+        // やまびこが消えるまで出力が続く
+        let last = response.iter().rposition(|s| *s != 0.0).unwrap();
+        assert!(response[last] < SILENCE as f32 * 2.0);
+    }
+
+    #[test]
+    fn reverb() {
+        let effects = Effects {
+            reverb: Reverb {
+                room: 50,
+                damp: 50,
+                mix: 50,
+            },
+            ..Default::default()
+        };
+        let response = impulse_response(&effects);
+        assert_eq!(response.len(), 100 + EffectChain::tail(&effects));
+        let energy = |samples: &[f32]| samples.iter().map(|s| s * s).sum::<f32>();
+        let tenth = SAMPLE_RATE as usize / 10;
+        let head = energy(&response[1..tenth]);
+        let end = energy(&response[response.len() - tenth..]);
+        assert!(head > 0.01);
+        // 出力が終わる頃には残響がほぼ消えている
+        assert!(end < head * 1e-4, "{} {}", head, end);
+    }
+
+    #[test]
+    fn chorus() {
+        let peak = |rate| {
+            let effects = Effects {
+                chorus: Chorus {
+                    rate,
+                    depth_ms: 5,
+                    mix: 100,
+                },
+                ..Default::default()
+            };
+            let response = impulse_response(&effects);
+            response
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+                .unwrap()
+                .0
+        };
+        // 揺れの中心は20ms = 882フレーム
+        assert_eq!(peak(0), 882);
+        // 揺らすと遅れが変わる
+        assert!(peak(10) > 882);
+    }
+}
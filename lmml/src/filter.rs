@@ -0,0 +1,184 @@
+use crate::oscillator::{Envelope, SAMPLE_RATE};
+
+/// フィルターの種類
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum FilterKind {
+    /// フィルターをかけない
+    #[default]
+    Off,
+    /// カットオフ周波数より高い音を削る
+    LowPass,
+    /// カットオフ周波数より低い音を削る
+    HighPass,
+    /// カットオフ周波数の付近の音だけを残す
+    BandPass,
+}
+
+impl FilterKind {
+    /// `@L`コマンドの番号から作る
+    pub const fn from_number(n: u32) -> Option<Self> {
+        match n {
+            0 => Some(Self::Off),
+            1 => Some(Self::LowPass),
+            2 => Some(Self::HighPass),
+            3 => Some(Self::BandPass),
+            _ => None,
+        }
+    }
+
+    /// `@L`コマンドの番号
+    pub const fn number(self) -> u32 {
+        match self {
+            Self::Off => 0,
+            Self::LowPass => 1,
+            Self::HighPass => 2,
+            Self::BandPass => 3,
+        }
+    }
+}
+
+/// フィルターの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// カットオフ周波数(Hz)
+    pub cutoff_hz: u32,
+    /// レゾナンス(百分率)。大きいほどカットオフ周波数の付近が強調される。
+    pub resonance: u32,
+}
+
+/// 音符ごとにカットオフ周波数を動かすエンベロープ
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct FilterEnvelope {
+    pub envelope: Envelope,
+    /// エンベロープが最大のときにカットオフ周波数を上げる幅(半音単位)。0なら動かさない。
+    pub depth: u32,
+}
+
+impl FilterEnvelope {
+    /// 音が鳴り始めてから`frame`フレーム目のカットオフ周波数
+    ///
+    /// `gate`は音を止めるフレーム、`length`は次の音が始まるフレーム。
+    pub fn cutoff(&self, cutoff_hz: u32, frame: u64, gate: u64, length: u64) -> f32 {
+        if self.depth == 0 {
+            return cutoff_hz as f32;
+        }
+        let level = self.envelope.level(frame, gate, length);
+        cutoff_hz as f32 * (self.depth as f32 * level / 12.0).exp2()
+    }
+}
+
+/// カットオフ周波数の上限(Hz)
+pub const MAX_CUTOFF_HZ: u32 = 20000;
+/// フィルターエンベロープの深さの上限(半音単位)
+pub const MAX_FILTER_DEPTH: u32 = 96;
+
+/// カットオフ周波数の下限(Hz)
+const MIN_CUTOFF_HZ: f32 = 20.0;
+
+/// 状態変数フィルター
+///
+/// 台形積分による実装のため、カットオフ周波数を急に動かしても発振しにくい。
+#[derive(Debug, Default, Clone)]
+pub struct StateVariableFilter {
+    ic1: f32,
+    ic2: f32,
+}
+
+impl StateVariableFilter {
+    pub const fn new() -> Self {
+        Self { ic1: 0.0, ic2: 0.0 }
+    }
+
+    /// 1フレームにフィルターをかける。`cutoff`は`filter.cutoff_hz`の代わりに使うカットオフ周波数。
+    pub fn process(&mut self, input: f32, filter: &Filter, cutoff: f32) -> f32 {
+        if filter.kind == FilterKind::Off {
+            return input;
+        }
+        let nyquist = SAMPLE_RATE as f32 / 2.0;
+        let cutoff = cutoff.clamp(MIN_CUTOFF_HZ, nyquist * 0.9);
+        let g = (std::f32::consts::PI * cutoff / SAMPLE_RATE as f32).tan();
+        // レゾナンス0でQ=0.5、100でQ=20
+        let q = 0.5 * 40f32.powf(filter.resonance.min(100) as f32 / 100.0);
+        let k = 1.0 / q;
+
+        let a1 = 1.0 / g.mul_add(g + k, 1.0);
+        let a2 = g * a1;
+        let a3 = g * a2;
+        let v3 = input - self.ic2;
+        let v1 = a1.mul_add(self.ic1, a2 * v3);
+        let v2 = a3.mul_add(v3, a2.mul_add(self.ic1, self.ic2));
+        self.ic1 = 2.0f32.mul_add(v1, -self.ic1);
+        self.ic2 = 2.0f32.mul_add(v2, -self.ic2);
+
+        match filter.kind {
+            FilterKind::Off => input,
+            FilterKind::LowPass => v2,
+            FilterKind::HighPass => k.mul_add(-v1, input - v2),
+            FilterKind::BandPass => k * v1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `frequency`Hzの正弦波にフィルターをかけたときの振幅
+    fn gain(filter: &Filter, frequency: f32) -> f32 {
+        let mut svf = StateVariableFilter::new();
+        let output: Vec<f32> = (0..SAMPLE_RATE)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let input = (2.0 * std::f32::consts::PI * frequency * t).sin();
+                svf.process(input, filter, filter.cutoff_hz as f32)
+            })
+            .collect();
+        // 立ち上がりを除いた最大値
+        output[SAMPLE_RATE as usize / 2..]
+            .iter()
+            .fold(0.0, |max, s| s.abs().max(max))
+    }
+
+    #[test]
+    fn response() {
+        let filter = |kind, resonance| Filter {
+            kind,
+            cutoff_hz: 1000,
+            resonance,
+        };
+        let low = filter(FilterKind::LowPass, 0);
+        assert!(gain(&low, 100.0) > 0.9);
+        assert!(gain(&low, 10000.0) < 0.05);
+
+        let high = filter(FilterKind::HighPass, 0);
+        assert!(gain(&high, 100.0) < 0.05);
+        assert!(gain(&high, 10000.0) > 0.9);
+
+        let band = filter(FilterKind::BandPass, 50);
+        assert!(gain(&band, 1000.0) > 0.9);
+        assert!(gain(&band, 100.0) < 0.1);
+        assert!(gain(&band, 10000.0) < 0.1);
+
+        // レゾナンスを上げるとカットオフ周波数の付近が強調される
+        assert!(gain(&filter(FilterKind::LowPass, 100), 1000.0) > 10.0);
+
+        let off = filter(FilterKind::Off, 100);
+        assert_eq!(StateVariableFilter::new().process(0.3, &off, 1000.0), 0.3);
+    }
+
+    #[test]
+    fn envelope() {
+        let sweep = FilterEnvelope {
+            envelope: Envelope::Fadeout,
+            depth: 24,
+        };
+        // 鳴り始めは2オクターブ上、音を止めると元のカットオフ周波数に戻る
+        assert_eq!(sweep.cutoff(500, 0, 100, 200), 2000.0);
+        assert_eq!(sweep.cutoff(500, 100, 100, 200), 500.0);
+        assert_eq!(FilterEnvelope::default().cutoff(500, 0, 100, 200), 500.0);
+    }
+}
@@ -3,5 +3,9 @@
 #![deny(clippy::nursery)]
 
 pub mod ast;
+pub mod effect;
+pub mod filter;
+pub mod midi;
 pub mod oscillator;
+pub mod printer;
 pub mod timeline;
@@ -0,0 +1,1032 @@
+use std::{collections::BTreeMap, fmt::Display, io};
+
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    num::{u4, u7, u15, u24, u28},
+};
+
+use crate::{
+    ast::{LmmlAst, LmmlCommand, NoteChar, NoteModifier, PERCUSSION},
+    oscillator::Drum,
+    timeline::{Element, Event, LmmlTimeline, Note, NoteType},
+};
+
+/// Standard MIDI Fileへの書き出しの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone)]
+pub struct MidiExportOptions {
+    /// 四分音符あたりのtick数
+    pub ticks_per_quarter: u16,
+    /// `@`コマンドの番号からGeneral MIDIのプログラム番号(0～127)への対応
+    ///
+    /// 対応が無い番号の波形には`default_program`が使用される。
+    pub programs: BTreeMap<u32, u8>,
+    pub default_program: u8,
+}
+
+impl Default for MidiExportOptions {
+    fn default() -> Self {
+        Self {
+            ticks_per_quarter: 480,
+            programs: BTreeMap::from([
+                (0, 81), // Lead 2 (sawtooth)
+                (1, 80), // Lead 1 (square)
+                (2, 80), // Lead 1 (square)
+                (3, 73), // Flute
+                (4, 79), // Ocarina
+            ]),
+            default_program: 81,
+        }
+    }
+}
+
+impl MidiExportOptions {
+    fn program(&self, waveform: u32) -> u8 {
+        self.programs
+            .get(&waveform)
+            .copied()
+            .unwrap_or(self.default_program)
+            .min(127)
+    }
+}
+
+const DEFAULT_TEMPO: u32 = 120;
+
+/// パンのコントロールチェンジの番号
+const PAN_CONTROLLER: u8 = 10;
+/// リバーブの深さのコントロールチェンジの番号
+const REVERB_CONTROLLER: u8 = 91;
+/// コーラスの深さのコントロールチェンジの番号
+const CHORUS_CONTROLLER: u8 = 93;
+
+/// 百分率をコントロールチェンジの値(0～127)に変換する
+const fn percent_to_midi(percent: u32) -> u32 {
+    if percent >= 100 {
+        127
+    } else {
+        percent * 127 / 100
+    }
+}
+
+/// General MIDIで打楽器に割り当てられているチャンネル(10チャンネル目)
+const PERCUSSION_CHANNEL: u4 = u4::new(9);
+
+/// チャンネルにそのチャンネル自身のMIDIチャンネルで出力される要素があるか
+fn uses_own_channel(channel: &[Element]) -> bool {
+    channel.iter().any(|element| {
+        matches!(
+            element,
+            Element::Note(Note {
+                note_type: NoteType::Single { .. } | NoteType::Chord { .. },
+                ..
+            }) | Element::Event(
+                Event::ChangePan(_) | Event::ChangeReverb(_) | Event::ChangeChorus(_)
+            )
+        )
+    })
+}
+
+/// LMMLのチャンネルごとに出力するMIDIチャンネルを決める
+///
+/// 打楽器を使う場合、打楽器のチャンネルと重なるチャンネルは空いているMIDIチャンネルに移す。
+fn midi_channels(timeline: &[Vec<Element>; 16]) -> io::Result<[u4; 16]> {
+    let mut channels = std::array::from_fn(|i| u4::new(i as u8));
+    let percussion = PERCUSSION_CHANNEL.as_int() as usize;
+    let uses_drums = timeline.iter().flatten().any(|element| {
+        matches!(
+            element,
+            Element::Note(Note {
+                note_type: NoteType::Drum { .. },
+                ..
+            })
+        )
+    });
+    if uses_drums && uses_own_channel(&timeline[percussion]) {
+        let free = (0..16)
+            .find(|&i| i != percussion && !uses_own_channel(&timeline[i]))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "打楽器とチャンネル9の音を出力するための空きMIDIチャンネルがありません",
+                )
+            })?;
+        channels[percussion] = u4::new(free as u8);
+    }
+    Ok(channels)
+}
+
+/// テンポの変化を表す区間の列
+///
+/// LMMLではチャンネルごとにテンポを持つが、SMFのテンポは全トラック共通である。
+/// そこで全チャンネルのテンポ変更を時刻順に並べたものを共通のテンポとし、
+/// 各イベントの時刻(ms)はこのテンポに従ってtickに変換する。
+/// これにより、チャンネル間でテンポが異なる場合でも発音のタイミングは保たれる。
+struct TempoMap {
+    /// (開始時刻[ms], 開始時刻[tick], テンポ)
+    segments: Vec<(f64, f64, u32)>,
+    ticks_per_quarter: f64,
+}
+
+impl TempoMap {
+    fn new(mut changes: Vec<(u64, u32)>, ticks_per_quarter: u16) -> Self {
+        changes.sort_by_key(|(ms, _)| *ms);
+        let ticks_per_quarter = ticks_per_quarter as f64;
+
+        let mut segments: Vec<(f64, f64, u32)> = vec![(0.0, 0.0, DEFAULT_TEMPO)];
+        for (ms, tempo) in changes {
+            let ms = ms as f64;
+            let &(start_ms, start_tick, current) = segments.last().unwrap();
+            if current == tempo {
+                continue;
+            }
+            if start_ms == ms {
+                segments.pop();
+                let previous = segments.last().map(|(_, _, t)| *t);
+                if previous != Some(tempo) {
+                    segments.push((start_ms, start_tick, tempo));
+                }
+            } else {
+                let tick =
+                    start_tick + (ms - start_ms) * current as f64 * ticks_per_quarter / 60000.0;
+                segments.push((ms, tick, tempo));
+            }
+        }
+
+        Self {
+            segments,
+            ticks_per_quarter,
+        }
+    }
+
+    fn ms_to_tick(&self, ms: u64) -> u64 {
+        let ms = ms as f64;
+        let &(start_ms, start_tick, tempo) = self
+            .segments
+            .iter()
+            .rev()
+            .find(|(start_ms, _, _)| *start_ms <= ms)
+            .unwrap();
+        (start_tick + (ms - start_ms) * tempo as f64 * self.ticks_per_quarter / 60000.0).round()
+            as u64
+    }
+}
+
+/// `Vec<(tick, イベント)>`をデルタタイム形式のトラックに変換する
+fn to_track(mut events: Vec<(u64, TrackEventKind<'static>)>) -> Vec<TrackEvent<'static>> {
+    // 同時刻のイベントはノートオフを先に処理する
+    events.sort_by_key(|(tick, kind)| {
+        let order = match kind {
+            TrackEventKind::Meta(_) => 0,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { .. },
+                ..
+            } => 1,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { .. },
+                ..
+            } => 3,
+            _ => 2,
+        };
+        (*tick, order)
+    });
+
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut last_tick = 0;
+    for (tick, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new((tick - last_tick).min(u28::max_value().as_int() as u64) as u32),
+            kind,
+        });
+        last_tick = tick;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+/// 周波数を最も近いMIDIのノート番号に変換する
+pub fn hz_to_notenumber(hz: f32) -> i32 {
+    12.0f32.mul_add((hz / 440.0).log2(), 69.0).round() as i32
+}
+
+fn hz_to_key(hz: f32) -> u8 {
+    hz_to_notenumber(hz).clamp(0, 127) as u8
+}
+
+fn volume_to_velocity(volume: f32) -> u7 {
+    u7::new((volume * 127.0 / 100.0).round().clamp(1.0, 127.0) as u8)
+}
+
+impl LmmlTimeline {
+    /// タイムラインをフォーマット1のStandard MIDI Fileとして書き出す
+    ///
+    /// 最初のトラックはテンポ情報のみを含み、それに続く16個のトラックが
+    /// それぞれLMMLのチャンネル0～15に対応する。チャンネル`i`の音符はMIDIチャンネル`i`で出力される。
+    /// ただし打楽器を使う場合、チャンネル9の音符は使われていないMIDIチャンネルで出力される。
+    pub fn write_smf(&self, options: &MidiExportOptions, out: impl io::Write) -> io::Result<()> {
+        let midi_channels = midi_channels(&self.timeline)?;
+        let mut tempo_changes = Vec::new();
+        for channel in self.timeline.iter() {
+            let mut time_ms: u64 = 0;
+            for element in channel {
+                match element {
+                    Element::Note(note) => time_ms += note.length_ms as u64,
+                    Element::Event(Event::ChangeTempo(tempo)) => {
+                        tempo_changes.push((time_ms, *tempo))
+                    }
+                    Element::Event(_) => {}
+                }
+            }
+        }
+        let tempo_map = TempoMap::new(tempo_changes, options.ticks_per_quarter);
+
+        let conductor = tempo_map
+            .segments
+            .iter()
+            .map(|&(_, tick, tempo)| {
+                (
+                    tick.round() as u64,
+                    // 遅すぎるテンポは表せる最も遅いテンポにする
+                    TrackEventKind::Meta(MetaMessage::Tempo(u24::new(
+                        (60_000_000 / tempo.max(1)).min(0xFF_FFFF),
+                    ))),
+                )
+            })
+            .collect();
+        let mut tracks = vec![to_track(conductor)];
+
+        for (i, channel) in self.timeline.iter().enumerate() {
+            let midi_channel = midi_channels[i];
+            let mut events = Vec::new();
+            let mut time_ms: u64 = 0;
+            let mut program = None;
+
+            for element in channel {
+                let note = match element {
+                    Element::Note(note) => note,
+                    Element::Event(event) => {
+                        let control = match event {
+                            Event::ChangePan(pan) => Some((PAN_CONTROLLER, (*pan).min(127))),
+                            Event::ChangeReverb(reverb) => {
+                                Some((REVERB_CONTROLLER, percent_to_midi(reverb.mix)))
+                            }
+                            Event::ChangeChorus(chorus) => {
+                                Some((CHORUS_CONTROLLER, percent_to_midi(chorus.mix)))
+                            }
+                            _ => None,
+                        };
+                        if let Some((controller, value)) = control {
+                            events.push((
+                                tempo_map.ms_to_tick(time_ms),
+                                TrackEventKind::Midi {
+                                    channel: midi_channel,
+                                    message: MidiMessage::Controller {
+                                        controller: u7::new(controller),
+                                        value: u7::new(value as u8),
+                                    },
+                                },
+                            ));
+                        }
+                        continue;
+                    }
+                };
+                // 打楽器はGeneral MIDIのパーカッションのチャンネルで鳴らす
+                let (keys, volume, waveform): (Vec<u8>, _, _) = match note.note_type {
+                    NoteType::Single {
+                        hz,
+                        volume,
+                        waveform,
+                        ..
+                    } => (vec![hz_to_key(hz)], volume, Some(waveform)),
+                    NoteType::Chord {
+                        ref hzs,
+                        volume,
+                        waveform,
+                        ..
+                    } => (
+                        hzs.iter().map(|hz| hz_to_key(*hz)).collect(),
+                        volume,
+                        Some(waveform),
+                    ),
+                    NoteType::Drum {
+                        ref drums, volume, ..
+                    } => (drums.iter().map(|d| d.gm_key()).collect(), volume, None),
+                    NoteType::Rest => (vec![], 0.0, None),
+                };
+
+                let start = tempo_map.ms_to_tick(time_ms);
+                let end = tempo_map.ms_to_tick(time_ms + note.sound_ms as u64);
+                time_ms += note.length_ms as u64;
+                if keys.is_empty() || end <= start {
+                    continue;
+                }
+
+                let note_channel = match waveform {
+                    Some(_) => midi_channel,
+                    None => PERCUSSION_CHANNEL,
+                };
+                if let Some(waveform) = waveform {
+                    let new_program = options.program(waveform);
+                    if program != Some(new_program) {
+                        program = Some(new_program);
+                        events.push((
+                            start,
+                            TrackEventKind::Midi {
+                                channel: midi_channel,
+                                message: MidiMessage::ProgramChange {
+                                    program: u7::new(new_program),
+                                },
+                            },
+                        ));
+                    }
+                }
+
+                let vel = volume_to_velocity(volume);
+                for key in keys {
+                    let key = u7::new(key);
+                    events.push((
+                        start,
+                        TrackEventKind::Midi {
+                            channel: note_channel,
+                            message: MidiMessage::NoteOn { key, vel },
+                        },
+                    ));
+                    events.push((
+                        end,
+                        TrackEventKind::Midi {
+                            channel: note_channel,
+                            message: MidiMessage::NoteOff { key, vel },
+                        },
+                    ));
+                }
+            }
+
+            tracks.push(to_track(events));
+        }
+
+        let smf = Smf {
+            header: Header::new(
+                Format::Parallel,
+                Timing::Metrical(u15::new(options.ticks_per_quarter.min(0x7fff))),
+            ),
+            tracks,
+        };
+        smf.write_std(out)
+    }
+}
+
+/// Standard MIDI Fileの読み込みの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone)]
+pub struct MidiImportOptions {
+    /// 量子化の単位となる音符の長さ
+    ///
+    /// 例えば32なら、音符の開始位置と長さは32分音符単位に丸められる。
+    pub quantize: u32,
+}
+
+impl Default for MidiImportOptions {
+    fn default() -> Self {
+        Self { quantize: 32 }
+    }
+}
+
+#[derive(Debug)]
+pub enum MidiImportError {
+    /// SMFとして解釈できなかった
+    Parse(midly::Error),
+    /// SMPTEタイムコード形式の時間単位には対応していない
+    UnsupportedTiming,
+    /// 量子化の単位が不正
+    InvalidQuantize(u32),
+    /// 同時に鳴る音が多すぎて16チャンネルに収まらない
+    TooManyVoices(usize),
+}
+
+impl Display for MidiImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "MIDIファイルの解析に失敗しました: {}", e),
+            Self::UnsupportedTiming => {
+                write!(f, "SMPTEタイムコード形式のMIDIファイルには対応していません")
+            }
+            Self::InvalidQuantize(q) => write!(f, "量子化の単位が不正です: {}", q),
+            Self::TooManyVoices(n) => write!(
+                f,
+                "同時に鳴る音が多すぎます (16チャンネルに対して{}声部が必要です)",
+                n
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MidiImportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// 量子化済みの音符
+#[derive(Debug, Clone, Copy)]
+struct RawNote {
+    start: u64,
+    end: u64,
+    key: u8,
+    vel: u8,
+}
+
+/// 同じ時刻に始まり同じ時刻に終わる音の集まり。1音なら単音、2音以上なら和音として出力する。
+#[derive(Debug, Clone)]
+struct Group {
+    start: u64,
+    end: u64,
+    keys: Vec<u8>,
+    vel: u8,
+}
+
+/// 量子化の単位で`units`の長さを表せる音符の長さを長い順に列挙する
+fn note_lengths(quantize: u32) -> Vec<(u64, u32, bool)> {
+    let mut lengths = Vec::new();
+    for n in (1..=quantize).filter(|n| quantize.is_multiple_of(*n)) {
+        let units = (quantize / n) as u64;
+        lengths.push((units, n, false));
+        if units.is_multiple_of(2) {
+            lengths.push((units * 3 / 2, n, true));
+        }
+    }
+    lengths.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    lengths.dedup_by_key(|(units, _, _)| *units);
+    lengths
+}
+
+/// 長さ`units`をいくつかの音符の長さの和に分解する
+fn split_length(mut units: u64, lengths: &[(u64, u32, bool)]) -> Vec<(u32, bool)> {
+    let mut result = Vec::new();
+    while units > 0 {
+        let &(u, n, dot) = lengths.iter().find(|(u, _, _)| *u <= units).unwrap();
+        result.push((n, dot));
+        units -= u;
+    }
+    result
+}
+
+const fn key_to_note(key: u8) -> (NoteChar, NoteModifier, i32) {
+    let (note, modifier) = match key % 12 {
+        0 => (NoteChar::C, NoteModifier::Natural),
+        1 => (NoteChar::C, NoteModifier::Sharp),
+        2 => (NoteChar::D, NoteModifier::Natural),
+        3 => (NoteChar::D, NoteModifier::Sharp),
+        4 => (NoteChar::E, NoteModifier::Natural),
+        5 => (NoteChar::F, NoteModifier::Natural),
+        6 => (NoteChar::F, NoteModifier::Sharp),
+        7 => (NoteChar::G, NoteModifier::Natural),
+        8 => (NoteChar::G, NoteModifier::Sharp),
+        9 => (NoteChar::A, NoteModifier::Natural),
+        10 => (NoteChar::A, NoteModifier::Sharp),
+        _ => (NoteChar::B, NoteModifier::Natural),
+    };
+    (note, modifier, key as i32 / 12 - 1)
+}
+
+/// 1つの声部をLMMLのコマンド列に変換する
+struct VoiceWriter<'a> {
+    commands: Vec<LmmlCommand>,
+    lengths: &'a [(u64, u32, bool)],
+    tempos: &'a [(u64, u32)],
+    tempo_index: usize,
+    cursor: u64,
+    octave: i32,
+    volume: u32,
+}
+
+impl VoiceWriter<'_> {
+    fn flush_tempo(&mut self) {
+        while let Some(&(tick, tempo)) = self.tempos.get(self.tempo_index) {
+            if tick > self.cursor {
+                break;
+            }
+            self.commands.push(LmmlCommand::SetTempo(tempo));
+            self.tempo_index += 1;
+        }
+    }
+
+    fn rest_until(&mut self, until: u64) {
+        while self.cursor < until {
+            self.flush_tempo();
+            let next = self
+                .tempos
+                .get(self.tempo_index)
+                .map_or(until, |(tick, _)| (*tick).min(until));
+            for (length, is_dotted) in split_length(next - self.cursor, self.lengths) {
+                self.commands.push(LmmlCommand::Rest {
+                    length: Some(length),
+                    is_dotted,
+                });
+            }
+            self.cursor = next;
+        }
+        self.flush_tempo();
+    }
+
+    fn set_octave(&mut self, octave: i32) {
+        match octave - self.octave {
+            0 => {}
+            1 => self.commands.push(LmmlCommand::IncreaseOctave),
+            -1 => self.commands.push(LmmlCommand::DecreaseOctave),
+            _ => {
+                self.commands
+                    .push(LmmlCommand::SetOctave(octave.max(0) as u32));
+                for _ in octave..0 {
+                    self.commands.push(LmmlCommand::DecreaseOctave);
+                }
+            }
+        }
+        self.octave = octave;
+    }
+
+    fn group(&mut self, group: &Group) {
+        self.rest_until(group.start);
+
+        let volume = (group.vel as u32 * 100 + 63) / 127;
+        if volume != self.volume {
+            self.commands.push(LmmlCommand::SetVolume(volume));
+            self.volume = volume;
+        }
+
+        let (_, _, octave) = key_to_note(group.keys[0]);
+        self.set_octave(octave);
+
+        // 音符の長さは最初の1つで表し、残りはタイでつなぐ
+        let mut lengths = split_length(group.end - group.start, self.lengths).into_iter();
+        let (length, is_dotted) = lengths.next().unwrap();
+        let command = if group.keys.len() == 1 {
+            let (note, modifier, _) = key_to_note(group.keys[0]);
+            LmmlCommand::Note {
+                note,
+                modifier,
+                length: Some(length),
+                is_dotted,
+            }
+        } else {
+            LmmlCommand::Chord {
+                notes: group
+                    .keys
+                    .iter()
+                    .map(|key| {
+                        let (note, modifier, _) = key_to_note(*key);
+                        (note, modifier)
+                    })
+                    .collect(),
+                length: Some(length),
+                is_dotted,
+            }
+        };
+        self.commands.push(command);
+        for (length, is_dotted) in lengths {
+            self.commands.push(LmmlCommand::TieLength {
+                length: Some(length),
+                is_dotted,
+            });
+        }
+        // 音符の途中のテンポ変更は音符の直後に反映する
+        self.cursor = group.end;
+        self.flush_tempo();
+    }
+}
+
+/// 同時に始まり同時に終わる音をLMMLの和音として表せるようにまとめる
+///
+/// LMMLの和音では各音は直前の音より高く、かつ1オクターブ以内に置かれるため、
+/// それを満たさない音は別のグループに分ける。
+fn make_groups(mut notes: Vec<RawNote>) -> Vec<Group> {
+    notes.sort_by_key(|n| (n.start, n.end, n.key));
+    let mut groups = Vec::new();
+    let mut i = 0;
+    while i < notes.len() {
+        let mut j = i;
+        while j < notes.len() && notes[j].start == notes[i].start && notes[j].end == notes[i].end {
+            j += 1;
+        }
+        let mut rest: Vec<RawNote> = notes[i..j].to_vec();
+        rest.dedup_by_key(|n| n.key);
+        while !rest.is_empty() {
+            let mut group = Group {
+                start: rest[0].start,
+                end: rest[0].end,
+                keys: vec![rest[0].key],
+                vel: rest[0].vel,
+            };
+            let mut remaining = Vec::new();
+            for note in rest.into_iter().skip(1) {
+                let prev = *group.keys.last().unwrap();
+                if note.key > prev && note.key - prev <= 12 {
+                    group.keys.push(note.key);
+                    group.vel = group.vel.max(note.vel);
+                } else {
+                    remaining.push(note);
+                }
+            }
+            groups.push(group);
+            rest = remaining;
+        }
+        i = j;
+    }
+    groups
+}
+
+/// 時間的に重ならないようにグループを声部に振り分ける
+fn allocate_voices(groups: Vec<Group>) -> Vec<Vec<Group>> {
+    let mut voices: Vec<Vec<Group>> = Vec::new();
+    for group in groups {
+        match voices
+            .iter_mut()
+            .find(|v| v.last().is_none_or(|last| last.end <= group.start))
+        {
+            Some(voice) => voice.push(group),
+            None => voices.push(vec![group]),
+        }
+    }
+    voices
+}
+
+/// Standard MIDI FileからLMMLのASTを生成する
+///
+/// 音符の位置と長さは`options.quantize`で指定された単位に量子化される。
+/// トラックとMIDIチャンネルの組ごとに、同時に鳴る音を和音または別の声部にまとめ、
+/// 声部ごとにLMMLのチャンネルを割り当てる。
+/// General MIDIの打楽器のチャンネル(MIDIチャンネル9)の音は`@7`の打楽器にする。
+pub fn import_smf(bytes: &[u8], options: &MidiImportOptions) -> Result<LmmlAst, MidiImportError> {
+    let smf = Smf::parse(bytes).map_err(MidiImportError::Parse)?;
+    let Timing::Metrical(ticks_per_quarter) = smf.header.timing else {
+        return Err(MidiImportError::UnsupportedTiming);
+    };
+    if options.quantize == 0 {
+        return Err(MidiImportError::InvalidQuantize(options.quantize));
+    }
+    let grid = (ticks_per_quarter.as_int() as u64 * 4).div_ceil(options.quantize as u64);
+    let grid = grid.max(1);
+    let quantize = |tick: u64| (tick + grid / 2) / grid;
+
+    let mut tempos: Vec<(u64, u32)> = Vec::new();
+    let mut sources: BTreeMap<(usize, u8), Vec<RawNote>> = BTreeMap::new();
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut tick: u64 = 0;
+        let mut pressed: BTreeMap<(u8, u8), Vec<(u64, u8)>> = BTreeMap::new();
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(us)) => {
+                    let tempo = (60_000_000.0 / us.as_int().max(1) as f64).round() as u32;
+                    tempos.push((quantize(tick), tempo.max(1)));
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    let channel = channel.as_int();
+                    let (key, vel, on) = match message {
+                        MidiMessage::NoteOn { key, vel } if vel > 0 => (key, vel, true),
+                        MidiMessage::NoteOn { key, vel } | MidiMessage::NoteOff { key, vel } => {
+                            (key, vel, false)
+                        }
+                        _ => continue,
+                    };
+                    let key = key.as_int();
+                    if on {
+                        pressed
+                            .entry((channel, key))
+                            .or_default()
+                            .push((tick, vel.as_int()));
+                    } else if let Some(starts) = pressed.get_mut(&(channel, key))
+                        && !starts.is_empty()
+                    {
+                        let (start, vel) = starts.remove(0);
+                        sources
+                            .entry((track_index, channel))
+                            .or_default()
+                            .push(RawNote {
+                                start: quantize(start),
+                                end: quantize(tick).max(quantize(start) + 1),
+                                key,
+                                vel,
+                            });
+                    }
+                }
+                _ => {}
+            }
+        }
+        // ノートオフの無い音はトラックの終わりまで伸ばす
+        for ((channel, key), starts) in pressed {
+            for (start, vel) in starts {
+                sources
+                    .entry((track_index, channel))
+                    .or_default()
+                    .push(RawNote {
+                        start: quantize(start),
+                        end: quantize(tick).max(quantize(start) + 1),
+                        key,
+                        vel,
+                    });
+            }
+        }
+    }
+    tempos.sort_by_key(|(tick, _)| *tick);
+
+    let voices: Vec<(bool, Vec<Group>)> = sources
+        .into_iter()
+        .flat_map(|((_, channel), mut notes)| {
+            // 打楽器はその打楽器を表す音名の音にする
+            let is_percussion = channel == PERCUSSION_CHANNEL.as_int();
+            if is_percussion {
+                for note in notes.iter_mut() {
+                    note.key = Drum::from_gm_key(note.key).notenumber();
+                }
+            }
+            allocate_voices(make_groups(notes))
+                .into_iter()
+                .map(move |voice| (is_percussion, voice))
+        })
+        .collect();
+    if voices.len() > 16 {
+        return Err(MidiImportError::TooManyVoices(voices.len()));
+    }
+
+    let lengths = note_lengths(options.quantize);
+    let mut commands = Vec::new();
+    for (i, (is_percussion, voice)) in voices.iter().enumerate() {
+        let mut writer = VoiceWriter {
+            commands: vec![LmmlCommand::SetChannel(i as u32)],
+            lengths: &lengths,
+            tempos: &tempos,
+            tempo_index: 0,
+            cursor: 0,
+            octave: 4,
+            volume: 20,
+        };
+        if *is_percussion {
+            writer.commands.push(LmmlCommand::SetWaveform(PERCUSSION));
+        }
+        if tempos.first().is_none_or(|(tick, _)| *tick > 0) {
+            writer.commands.push(LmmlCommand::SetTempo(DEFAULT_TEMPO));
+        }
+        writer.flush_tempo();
+        for group in voice {
+            writer.group(group);
+        }
+        commands.extend(writer.commands);
+    }
+
+    Ok(LmmlAst(commands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tempo_map() {
+        let map = TempoMap::new(vec![(0, 60), (2000, 120)], 480);
+        assert_eq!(map.ms_to_tick(0), 0);
+        assert_eq!(map.ms_to_tick(1000), 480);
+        assert_eq!(map.ms_to_tick(2000), 960);
+        assert_eq!(map.ms_to_tick(2500), 1440);
+    }
+
+    #[test]
+    fn split_length() {
+        let lengths = note_lengths(32);
+        assert_eq!(super::split_length(8, &lengths), vec![(4, false)]);
+        assert_eq!(super::split_length(12, &lengths), vec![(4, true)]);
+        assert_eq!(
+            super::split_length(10, &lengths),
+            vec![(4, false), (16, false)]
+        );
+        assert_eq!(
+            super::split_length(40, &lengths),
+            vec![(1, false), (4, false)]
+        );
+    }
+
+    #[test]
+    fn export_and_import() {
+        use crate::ast::{EvalEnv, LmmlCommand::*, NoteChar::*, NoteModifier::*};
+
+        let ast = LmmlAst(vec![
+            SetTempo(150),
+            Note {
+                note: C,
+                modifier: Sharp,
+                length: Some(8),
+                is_dotted: false,
+            },
+            Rest {
+                length: Some(8),
+                is_dotted: false,
+            },
+            Chord {
+                notes: vec![(C, Natural), (E, Natural), (G, Natural)],
+                length: Some(2),
+                is_dotted: true,
+            },
+            Note {
+                note: A,
+                modifier: Natural,
+                length: Some(1),
+                is_dotted: false,
+            },
+            TieLength {
+                length: Some(8),
+                is_dotted: false,
+            },
+        ]);
+        let mut smf = Vec::new();
+        ast.to_timeline(&mut EvalEnv::default())
+            .unwrap()
+            .write_smf(&MidiExportOptions::default(), &mut smf)
+            .unwrap();
+        let imported = import_smf(&smf, &MidiImportOptions::default()).unwrap();
+
+        let mut expected = vec![SetChannel(0)];
+        expected.extend(ast.0);
+        assert_eq!(imported, LmmlAst(expected));
+    }
+
+    #[test]
+    fn export_drums() {
+        use crate::{
+            ast::{EvalEnv, LmmlCommand::*, NoteChar::*, NoteModifier::*, PERCUSSION},
+            oscillator::Drum,
+        };
+
+        let ast = LmmlAst(vec![
+            SetChannel(3),
+            SetWaveform(PERCUSSION),
+            Chord {
+                notes: vec![(C, Natural), (E, Natural)],
+                length: None,
+                is_dotted: false,
+            },
+        ]);
+        let mut smf = Vec::new();
+        ast.to_timeline(&mut EvalEnv::default())
+            .unwrap()
+            .write_smf(&MidiExportOptions::default(), &mut smf)
+            .unwrap();
+        let smf = Smf::parse(&smf).unwrap();
+        let notes: Vec<_> = smf
+            .tracks
+            .iter()
+            .flatten()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, .. },
+                } => Some((channel.as_int(), key.as_int())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![(9, Drum::Kick.gm_key()), (9, Drum::ClosedHiHat.gm_key())]
+        );
+    }
+
+    #[test]
+    fn export_and_import_drums() {
+        use crate::ast::{EvalEnv, LmmlCommand::*, NoteChar::*, NoteModifier::*, PERCUSSION};
+
+        let note = |note| LmmlCommand::Note {
+            note,
+            modifier: Natural,
+            length: Some(8),
+            is_dotted: false,
+        };
+        let ast = LmmlAst(vec![
+            SetChannel(0),
+            SetWaveform(PERCUSSION),
+            SetTempo(120),
+            note(C),
+            note(D),
+            Chord {
+                notes: vec![(C, Natural), (E, Natural)],
+                length: Some(8),
+                is_dotted: false,
+            },
+            note(B),
+        ]);
+        let mut smf = Vec::new();
+        ast.to_timeline(&mut EvalEnv::default())
+            .unwrap()
+            .write_smf(&MidiExportOptions::default(), &mut smf)
+            .unwrap();
+        let imported = import_smf(&smf, &MidiImportOptions::default()).unwrap();
+        assert_eq!(imported, ast);
+
+        for drum in [
+            Drum::Kick,
+            Drum::Snare,
+            Drum::ClosedHiHat,
+            Drum::OpenHiHat,
+            Drum::LowTom,
+            Drum::HighTom,
+            Drum::Crash,
+        ] {
+            assert_eq!(Drum::from_gm_key(drum.gm_key()), drum);
+            assert_eq!(Drum::from_notenumber(drum.notenumber() as i32), drum);
+        }
+    }
+
+    #[test]
+    fn export_drums_with_channel_9() {
+        use crate::{
+            ast::{EvalEnv, LmmlCommand::*, NoteChar::*, NoteModifier::*, PERCUSSION},
+            oscillator::Drum,
+        };
+
+        let note = |note| LmmlCommand::Note {
+            note,
+            modifier: Natural,
+            length: None,
+            is_dotted: false,
+        };
+        let ast = LmmlAst(vec![
+            SetChannel(9),
+            note(C),
+            SetChannel(3),
+            SetWaveform(PERCUSSION),
+            note(C),
+        ]);
+        let mut smf = Vec::new();
+        ast.to_timeline(&mut EvalEnv::default())
+            .unwrap()
+            .write_smf(&MidiExportOptions::default(), &mut smf)
+            .unwrap();
+        let smf = Smf::parse(&smf).unwrap();
+        let notes: Vec<_> = smf
+            .tracks
+            .iter()
+            .flatten()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, .. },
+                } => Some((channel.as_int(), key.as_int())),
+                _ => None,
+            })
+            .collect();
+        // チャンネル9の音は空いているMIDIチャンネル0に移る
+        assert_eq!(notes, vec![(9, Drum::Kick.gm_key()), (0, 60)]);
+
+        // 空いているMIDIチャンネルが無い場合は書き出せない
+        let mut commands = vec![];
+        for channel in 0..16 {
+            commands.extend([SetChannel(channel), note(C)]);
+        }
+        commands.extend([SetWaveform(PERCUSSION), note(C)]);
+        let error = LmmlAst(commands)
+            .to_timeline(&mut EvalEnv::default())
+            .unwrap()
+            .write_smf(&MidiExportOptions::default(), &mut Vec::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn export_slow_tempo() {
+        use crate::ast::{EvalEnv, LmmlCommand::*};
+
+        let ast = LmmlAst(vec![
+            SetTempo(1),
+            Rest {
+                length: None,
+                is_dotted: false,
+            },
+        ]);
+        let mut smf = Vec::new();
+        ast.to_timeline(&mut EvalEnv::default())
+            .unwrap()
+            .write_smf(&MidiExportOptions::default(), &mut smf)
+            .unwrap();
+        let smf = Smf::parse(&smf).unwrap();
+        let tempos: Vec<_> = smf
+            .tracks
+            .iter()
+            .flatten()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => Some(tempo.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert!(!tempos.is_empty());
+        assert!(tempos.iter().all(|t| *t == 0xFF_FFFF), "{:?}", tempos);
+    }
+
+    #[test]
+    fn hz_to_notenumber_roundtrip() {
+        for n in 0..128 {
+            assert_eq!(hz_to_notenumber(crate::ast::notenumber_to_hz(n)), n);
+        }
+    }
+}
@@ -1,41 +1,193 @@
-use std::{f32::consts::PI, num::NonZero};
+use std::{io, num::NonZero, sync::Arc};
 
 use rodio::{
     Source,
     source::{TakeDuration, Zero},
 };
 
+use crate::{
+    effect::{EffectChain, Effects},
+    filter::{Filter, FilterEnvelope, StateVariableFilter},
+};
+
 pub const SAMPLE_RATE: u32 = 44100;
 pub const SAMPLE_RATE_NONZERO: NonZero<u32> = NonZero::new(SAMPLE_RATE).unwrap();
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Waveform {
     Zero,
     Saw,
     Square(f32),
     Triangle,
     Sine,
+    /// ファミコンと同じ長周期のノイズ
+    Noise,
+    /// ファミコンと同じ短周期(93ステップ)のノイズ。音程のある金属的な音になる。
+    PeriodicNoise,
+    /// 1周期分の波形を階段状に再生する。値は-1～1。
+    Wavetable(Vec<f32>),
 }
 
+/// ファミコンのノイズと同じ15ビットの線形帰還シフトレジスタ
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy)]
+struct Lfsr(u16);
+
+impl Lfsr {
+    const fn new() -> Self {
+        Self(1)
+    }
+
+    /// レジスタを1ステップ進める。`short`なら短周期のモードになる。
+    const fn clock(&mut self, short: bool) {
+        let tap = if short { 6 } else { 1 };
+        let feedback = (self.0 ^ (self.0 >> tap)) & 1;
+        self.0 = (self.0 >> 1) | (feedback << 14);
+    }
+
+    /// 現在の出力(-1または1)
+    const fn value(self) -> f64 {
+        if self.0 & 1 == 0 { 1.0 } else { -1.0 }
+    }
+}
+
+/// ノイズのレジスタを進める速さ(音の周波数に対する倍率)
+const NOISE_CLOCK_RATIO: f64 = 16.0;
+
+/// 1周期の中の位置が`t`(0～1)のときの、不連続な変化を滑らかにするための補正値(PolyBLEP)
+///
+/// `dt`は1サンプルあたりに進む位置。
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let t = t / dt;
+        t.mul_add(-t, t + t) - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t.mul_add(t, t + t) + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// ビブラートの深さの上限(セント単位)
+pub const MAX_VIBRATO_DEPTH: u32 = 1200;
+/// ビブラートの速さの上限(0.1Hz単位)
+pub const MAX_VIBRATO_RATE: u32 = 200;
+
+/// 音の高さを周期的に揺らすビブラート
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Vibrato {
+    /// 揺れの深さ(セント単位)。0なら揺らさない。
+    pub depth: u32,
+    /// 揺れの速さ(0.1Hz単位)
+    pub rate: u32,
+    /// 鳴り始めてから揺れ始めるまでの時間(ミリ秒)
+    pub delay_ms: u32,
+}
+
+impl Vibrato {
+    /// 鳴り始めてから`frame`フレーム目の周波数の倍率
+    fn ratio(&self, frame: u64) -> f64 {
+        let delay = ms_to_frames(self.delay_ms);
+        if self.depth == 0 || frame < delay {
+            return 1.0;
+        }
+        let t = (frame - delay) as f64 / SAMPLE_RATE as f64;
+        let swing = (2.0 * std::f64::consts::PI * self.rate as f64 / 10.0 * t).sin();
+        (self.depth as f64 * swing / 1200.0).exp2()
+    }
+}
+
+/// 指定した周波数で鳴る波形
+///
+/// 位相を浮動小数点数で保持するため、周波数がサンプリング周波数で割り切れなくても音程がずれない。
+/// ノコギリ波・矩形波・三角波はPolyBLEPによって帯域制限されている。
+/// [`Vibrato`]を指定すると周波数が周期的に揺れ、[`NoteWave::with_slide`]で鳴り始めの音の高さを滑らかに変えられる。
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
 pub struct NoteWave {
-    frame: usize,
+    /// 1周期の中の位置(0～1)
+    phase: f64,
     waveform: Waveform,
     frequency: f32,
     amplitude: f32,
+    /// 三角波を作るために矩形波を積分した値
+    integral: f64,
+    lfsr: Lfsr,
+    vibrato: Vibrato,
+    /// 鳴り始めの周波数。`slide_frames`フレームかけて`frequency`に近づく。
+    slide_from: f32,
+    slide_frames: u64,
+    /// 鳴り始めてからのフレーム数
+    frame: u64,
 }
 
 impl NoteWave {
-    pub const fn new(waveform: Waveform, frequency: f32, amplitude: f32) -> Self {
+    pub fn new(waveform: Waveform, frequency: f32, amplitude: f32) -> Self {
         Self {
-            frame: 0,
+            phase: 0.0,
             waveform,
             frequency,
             amplitude,
+            integral: -0.25,
+            lfsr: Lfsr::new(),
+            vibrato: Vibrato::default(),
+            slide_from: frequency,
+            slide_frames: 0,
+            frame: 0,
         }
     }
+
+    /// 周波数をビブラートで揺らす
+    pub const fn with_vibrato(mut self, vibrato: Vibrato) -> Self {
+        self.vibrato = vibrato;
+        self
+    }
+
+    /// `from`の周波数で鳴り始め、`time_ms`ミリ秒かけて本来の周波数まで音の高さを滑らかに変える
+    pub const fn with_slide(mut self, from: f32, time_ms: u32) -> Self {
+        self.slide_from = from;
+        self.slide_frames = ms_to_frames(time_ms);
+        self
+    }
+
+    /// 現在のフレームの周波数
+    fn current_frequency(&self) -> f64 {
+        let frequency = self.frequency as f64;
+        let frequency = if self.frame < self.slide_frames && self.slide_from > 0.0 {
+            // 音の高さが一定の速さで変わるように、周波数は指数関数的に変える
+            let progress = self.frame as f64 / self.slide_frames as f64;
+            let from = self.slide_from as f64;
+            from * (frequency / from).powf(progress)
+        } else {
+            frequency
+        };
+        frequency * self.vibrato.ratio(self.frame)
+    }
+
+    /// 振幅が1の矩形波
+    fn square(t: f64, dt: f64, pulse_width: f64) -> f64 {
+        let naive = if t < pulse_width { 1.0 } else { -1.0 };
+        naive + poly_blep(t, dt) - poly_blep((t - pulse_width).rem_euclid(1.0), dt)
+    }
+
+    /// 1周期分の波形`table`を階段状に再生した値
+    ///
+    /// 現在の段の始まりと次の段の始まりの不連続な変化をPolyBLEPで滑らかにする。
+    fn wavetable(table: &[f32], t: f64, dt: f64) -> f64 {
+        let len = table.len();
+        let i = ((t * len as f64) as usize).min(len - 1);
+        // `k`番目の段の始まりでの変化の大きさの半分
+        let step = |k: usize| (table[k % len] - table[(k + len - 1) % len]) as f64 / 2.0;
+        let start = i as f64 / len as f64;
+        let end = (i + 1) as f64 / len as f64;
+        step(i).mul_add(
+            poly_blep(t - start, dt),
+            step(i + 1).mul_add(poly_blep((t - end).rem_euclid(1.0), dt), table[i] as f64),
+        )
+    }
 }
 
 impl Source for NoteWave {
@@ -60,55 +212,37 @@ impl Iterator for NoteWave {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.waveform {
-            Waveform::Zero => Some(0.0),
-            Waveform::Saw => {
-                self.frame += 1;
-                if self.frame > (SAMPLE_RATE as f32 / self.frequency) as usize {
-                    self.frame = 0;
-                }
-                Some(
-                    (self.frame as f32 / (SAMPLE_RATE as f32 / self.frequency) - 0.5)
-                        * 2.0
-                        * self.amplitude,
-                )
-            }
-            Waveform::Square(pulse_width) => {
-                self.frame += 1;
-                if self.frame > (SAMPLE_RATE as f32 / self.frequency) as usize {
-                    self.frame = 0;
-                }
-                if self.frame < ((SAMPLE_RATE as f32 / self.frequency) * pulse_width) as usize {
-                    Some(self.amplitude)
-                } else {
-                    Some(-self.amplitude)
-                }
-            }
+        let frequency = self.current_frequency();
+        self.frame += 1;
+
+        let t = self.phase;
+        let dt = (frequency / SAMPLE_RATE as f64).min(0.5);
+        let mut next_phase = (t + dt).fract();
+        let value = match self.waveform {
+            Waveform::Zero => 0.0,
+            Waveform::Saw => 2.0f64.mul_add(t, -1.0) - poly_blep(t, dt),
+            Waveform::Square(pulse_width) => Self::square(t, dt, pulse_width as f64),
             Waveform::Triangle => {
-                self.frame += 1;
-                if self.frame > (SAMPLE_RATE as f32 / self.frequency) as usize {
-                    self.frame = 0;
-                }
-                if self.frame < ((SAMPLE_RATE as f32 / self.frequency) * 0.5) as usize {
-                    Some(
-                        (2.0 * self.frame as f32 / (SAMPLE_RATE as f32 / self.frequency) - 0.5)
-                            * 2.0
-                            * self.amplitude,
-                    )
-                } else {
-                    Some(
-                        (1.5 - 2.0 * self.frame as f32 / (SAMPLE_RATE as f32 / self.frequency))
-                            * 2.0
-                            * self.amplitude,
-                    )
-                }
+                // 帯域制限された矩形波を積分する
+                self.integral = dt.mul_add(Self::square(t, dt, 0.5), (1.0 - dt) * self.integral);
+                4.0 * self.integral
             }
-            Waveform::Sine => {
-                self.frame = self.frame.wrapping_add(1);
-                let value = 2.0 * PI * self.frequency * self.frame as f32 / SAMPLE_RATE as f32;
-                Some(self.amplitude * value.sin())
+            Waveform::Sine => (2.0 * std::f64::consts::PI * t).sin(),
+            Waveform::Wavetable(ref table) if table.is_empty() => 0.0,
+            Waveform::Wavetable(ref table) => Self::wavetable(table, t, dt),
+            Waveform::Noise | Waveform::PeriodicNoise => {
+                // ノイズは周波数に比例した速さでレジスタを進める
+                let short = matches!(self.waveform, Waveform::PeriodicNoise);
+                let phase = t + frequency * NOISE_CLOCK_RATIO / SAMPLE_RATE as f64;
+                for _ in 0..phase as u64 {
+                    self.lfsr.clock(short);
+                }
+                next_phase = phase.fract();
+                self.lfsr.value()
             }
-        }
+        };
+        self.phase = next_phase;
+        Some(value as f32 * self.amplitude)
     }
 }
 
@@ -156,13 +290,673 @@ impl Iterator for ChordWave {
     }
 }
 
+/// FM音源のオペレーター
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FmOperator {
+    /// 音の周波数に対する倍率。0は0.5倍を表す。
+    pub ratio: u32,
+    /// 出力の大きさ(百分率)。モジュレーターの場合は100で1周期分位相をずらす。
+    pub level: u32,
+    pub envelope: Envelope,
+}
+
+/// FM音源の音色
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FmVoice {
+    /// オペレーターの接続(アルゴリズム)。2オペレーターなら0～1、4オペレーターなら0～7。
+    pub algorithm: u32,
+    /// 1番目のオペレーターの自己フィードバックの強さ(0～7)
+    pub feedback: u32,
+    /// 2つまたは4つのオペレーター
+    pub operators: Vec<FmOperator>,
+}
+
+/// オペレーターの接続
+struct Algorithm {
+    /// 各オペレーターを変調するオペレーター
+    modulators: [&'static [usize]; 4],
+    /// 出力されるオペレーター
+    carriers: &'static [usize],
+}
+
+const TWO_OPERATOR_ALGORITHMS: [Algorithm; 2] = [
+    Algorithm {
+        modulators: [&[], &[0], &[], &[]],
+        carriers: &[1],
+    },
+    Algorithm {
+        modulators: [&[], &[], &[], &[]],
+        carriers: &[0, 1],
+    },
+];
+
+/// YM2203などのOPN系の音源と同じ接続
+const FOUR_OPERATOR_ALGORITHMS: [Algorithm; 8] = [
+    Algorithm {
+        modulators: [&[], &[0], &[1], &[2]],
+        carriers: &[3],
+    },
+    Algorithm {
+        modulators: [&[], &[], &[0, 1], &[2]],
+        carriers: &[3],
+    },
+    Algorithm {
+        modulators: [&[], &[], &[1], &[0, 2]],
+        carriers: &[3],
+    },
+    Algorithm {
+        modulators: [&[], &[0], &[], &[1, 2]],
+        carriers: &[3],
+    },
+    Algorithm {
+        modulators: [&[], &[0], &[], &[2]],
+        carriers: &[1, 3],
+    },
+    Algorithm {
+        modulators: [&[], &[0], &[0], &[0]],
+        carriers: &[1, 2, 3],
+    },
+    Algorithm {
+        modulators: [&[], &[0], &[], &[]],
+        carriers: &[1, 2, 3],
+    },
+    Algorithm {
+        modulators: [&[], &[], &[], &[]],
+        carriers: &[0, 1, 2, 3],
+    },
+];
+
+/// フィードバックの強さに対する位相のずれ(周期単位)
+const FEEDBACK_LEVELS: [f64; 8] = [0.0, 1.0 / 32.0, 1.0 / 16.0, 1.0 / 8.0, 0.25, 0.5, 1.0, 2.0];
+
+impl FmVoice {
+    /// オペレーターの数とアルゴリズムの番号が正しいかどうか
+    pub fn is_valid(&self) -> bool {
+        self.algorithm().is_some() && (self.feedback as usize) < FEEDBACK_LEVELS.len()
+    }
+
+    fn algorithm(&self) -> Option<&'static Algorithm> {
+        let algorithms: &[Algorithm] = match self.operators.len() {
+            2 => &TWO_OPERATOR_ALGORITHMS,
+            4 => &FOUR_OPERATOR_ALGORITHMS,
+            _ => return None,
+        };
+        algorithms.get(self.algorithm as usize)
+    }
+}
+
+/// FM音源の音の状態
+#[derive(Debug, Clone)]
+struct FmNote {
+    frequency: f64,
+    /// 各オペレーターの位相(0～1)
+    phases: [f64; 4],
+    /// 1番目のオペレーターの直前2回の出力
+    feedback: [f64; 2],
+}
+
+/// FM音源で合成した波形。和音も鳴らすことができる。
+///
+/// 各オペレーターのエンベロープを適用し、指定された長さで終了する。
+#[derive(Debug, Clone)]
+pub struct FmWave {
+    voice: FmVoice,
+    notes: Vec<FmNote>,
+    amplitude: f32,
+    frame: u64,
+    gate: u64,
+    length: u64,
+}
+
+impl FmWave {
+    /// `frequencies`の音を`sound_ms`ミリ秒の間鳴らし、`length_ms`ミリ秒で終了する波形を作る
+    pub fn new(
+        voice: FmVoice,
+        frequencies: &[f32],
+        amplitude: f32,
+        sound_ms: u32,
+        length_ms: u32,
+    ) -> Self {
+        let notes = frequencies
+            .iter()
+            .map(|frequency| FmNote {
+                frequency: *frequency as f64,
+                phases: [0.0; 4],
+                feedback: [0.0; 2],
+            })
+            .collect();
+        Self {
+            voice,
+            notes,
+            amplitude,
+            frame: 0,
+            gate: ms_to_frames(sound_ms),
+            length: ms_to_frames(length_ms),
+        }
+    }
+}
+
+impl Source for FmWave {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> NonZero<u16> {
+        const { NonZero::new(1).unwrap() }
+    }
+
+    fn sample_rate(&self) -> NonZero<u32> {
+        SAMPLE_RATE_NONZERO
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+impl Iterator for FmWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.frame >= self.length {
+            return None;
+        }
+        let Some(algorithm) = self.voice.algorithm() else {
+            self.frame += 1;
+            return Some(0.0);
+        };
+
+        let mut levels = [0.0; 4];
+        let mut ratios = [0.0; 4];
+        for (i, operator) in self.voice.operators.iter().enumerate() {
+            let envelope = operator.envelope.level(self.frame, self.gate, self.length);
+            levels[i] = operator.level as f64 / 100.0 * envelope as f64;
+            ratios[i] = if operator.ratio == 0 {
+                0.5
+            } else {
+                operator.ratio as f64
+            };
+        }
+        let feedback = FEEDBACK_LEVELS
+            .get(self.voice.feedback as usize)
+            .copied()
+            .unwrap_or(0.0);
+        self.frame += 1;
+
+        let mut sum = 0.0;
+        for note in self.notes.iter_mut() {
+            // 変調するオペレーターは常に番号が小さいため、番号の順に計算できる
+            let mut outputs = [0.0; 4];
+            for i in 0..self.voice.operators.len() {
+                let modulation = if i == 0 {
+                    feedback * (note.feedback[0] + note.feedback[1]) / 2.0
+                } else {
+                    algorithm.modulators[i].iter().map(|j| outputs[*j]).sum()
+                };
+                let phase = note.phases[i] + modulation;
+                outputs[i] = (2.0 * std::f64::consts::PI * phase).sin() * levels[i];
+                note.phases[i] = note
+                    .frequency
+                    .mul_add(ratios[i] / SAMPLE_RATE as f64, note.phases[i])
+                    .fract();
+            }
+            note.feedback = [note.feedback[1], outputs[0]];
+            sum += algorithm.carriers.iter().map(|i| outputs[*i]).sum::<f64>()
+                / algorithm.carriers.len() as f64;
+        }
+        Some(sum as f32 * self.amplitude)
+    }
+}
+
+/// 音声ファイルから読み込んだモノラルの波形
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(PartialEq, Clone)]
+pub struct SampleData {
+    /// -1～1の値。複数チャンネルの音声は平均してモノラルにする。
+    pub frames: Arc<[f32]>,
+    pub sample_rate: u32,
+}
+
+impl std::fmt::Debug for SampleData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SampleData")
+            .field("frames", &self.frames.len())
+            .field("sample_rate", &self.sample_rate)
+            .finish()
+    }
+}
+
+impl SampleData {
+    /// WAVファイルを読み込む
+    pub fn from_wav(reader: impl io::Read) -> io::Result<Self> {
+        let invalid = |e: hound::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+        let reader = hound::WavReader::new(reader).map_err(invalid)?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()
+                    .map_err(invalid)?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let frames = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        Ok(Self {
+            frames,
+            sample_rate: spec.sample_rate,
+        })
+    }
+}
+
+/// 音声ファイルを使った音色
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
+pub struct SampleInstrument {
+    pub data: SampleData,
+    /// 元の音の高さを表すノート番号
+    pub root: u32,
+    /// ループする範囲(フレーム)。音は`end`に達すると`start`に戻る。
+    pub loop_frames: Option<(u32, u32)>,
+}
+
+/// 音声ファイルの波形を音の高さに合わせて再生速度を変えて鳴らす。和音も鳴らすことができる。
+#[derive(Debug, Clone)]
+pub struct SampleWave {
+    instrument: SampleInstrument,
+    /// 各音の再生位置と、1サンプルあたりに進むフレーム数
+    voices: Vec<(f64, f64)>,
+    amplitude: f32,
+}
+
+impl SampleWave {
+    pub fn new(instrument: SampleInstrument, frequencies: &[f32], amplitude: f32) -> Self {
+        let root = crate::ast::notenumber_to_hz(instrument.root as i32) as f64;
+        let rate = instrument.data.sample_rate as f64 / SAMPLE_RATE as f64;
+        let voices = frequencies
+            .iter()
+            .map(|frequency| (0.0, *frequency as f64 / root * rate))
+            .collect();
+        Self {
+            instrument,
+            voices,
+            amplitude,
+        }
+    }
+}
+
+impl Source for SampleWave {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> NonZero<u16> {
+        const { NonZero::new(1).unwrap() }
+    }
+
+    fn sample_rate(&self) -> NonZero<u32> {
+        SAMPLE_RATE_NONZERO
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+impl Iterator for SampleWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frames = &self.instrument.data.frames;
+        let mut sum = 0.0;
+        for (position, step) in self.voices.iter_mut() {
+            if let Some((start, end)) = self.instrument.loop_frames
+                && *position >= end as f64
+            {
+                // 1フレームでループの長さより多く進む場合も、ループの中に戻す
+                let start = start as f64;
+                *position = start + (*position - start).rem_euclid(end as f64 - start);
+            }
+            // 隣り合うフレームの間を線形補間する
+            let i = *position as usize;
+            if i < frames.len() {
+                let next = frames.get(i + 1).copied().unwrap_or(0.0);
+                sum += (next - frames[i]).mul_add(position.fract() as f32, frames[i]);
+            }
+            *position += *step;
+        }
+        Some(sum * self.amplitude)
+    }
+}
+
+/// 打楽器の種類
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Drum {
+    Kick,
+    Snare,
+    ClosedHiHat,
+    OpenHiHat,
+    LowTom,
+    HighTom,
+    Crash,
+}
+
+impl Drum {
+    /// ノート番号の音名に対応する打楽器
+    ///
+    /// オクターブは無視される。`c`がバスドラム、`d`がスネア、`e`がクローズハイハット、
+    /// `f`がオープンハイハット、`g`がロータム、`a`がハイタム、`b`がクラッシュシンバルで、
+    /// `+`の付いた音は1つ下の音名と同じ打楽器になる。
+    pub const fn from_notenumber(notenumber: i32) -> Self {
+        match notenumber.rem_euclid(12) {
+            0 | 1 => Self::Kick,
+            2 | 3 => Self::Snare,
+            4 => Self::ClosedHiHat,
+            5 | 6 => Self::OpenHiHat,
+            7 | 8 => Self::LowTom,
+            9 | 10 => Self::HighTom,
+            _ => Self::Crash,
+        }
+    }
+
+    /// 打楽器を表す音名の、オクターブ4でのノート番号。[`Self::from_notenumber`]の逆。
+    pub const fn notenumber(self) -> u8 {
+        match self {
+            Self::Kick => 60,
+            Self::Snare => 62,
+            Self::ClosedHiHat => 64,
+            Self::OpenHiHat => 65,
+            Self::LowTom => 67,
+            Self::HighTom => 69,
+            Self::Crash => 71,
+        }
+    }
+
+    /// General MIDIのパーカッションのキー番号に最も近い打楽器。[`Self::gm_key`]の逆。
+    pub const fn from_gm_key(key: u8) -> Self {
+        match key {
+            ..=36 => Self::Kick,
+            37..=40 => Self::Snare,
+            42 | 44 => Self::ClosedHiHat,
+            46 => Self::OpenHiHat,
+            41 | 43 | 45 | 47 => Self::LowTom,
+            48 | 50 => Self::HighTom,
+            _ => Self::Crash,
+        }
+    }
+
+    /// General MIDIのパーカッションのキー番号
+    pub const fn gm_key(self) -> u8 {
+        match self {
+            Self::Kick => 36,
+            Self::Snare => 38,
+            Self::ClosedHiHat => 42,
+            Self::OpenHiHat => 46,
+            Self::LowTom => 45,
+            Self::HighTom => 50,
+            Self::Crash => 49,
+        }
+    }
+
+    /// 鳴り始めてから`time`秒後の値
+    ///
+    /// `phase`は音程のある成分の位相、`noise`と`high_noise`はノイズとその高音成分。
+    fn sample(self, time: f64, phase: &mut f64, noise: f64, high_noise: f64) -> f64 {
+        // 周波数が`to`Hzに向かって下がっていく正弦波
+        let mut sweep = |from: f64, to: f64, speed: f64| {
+            let frequency = (from - to).mul_add((-time * speed).exp(), to);
+            *phase = (*phase + frequency / SAMPLE_RATE as f64).fract();
+            (2.0 * std::f64::consts::PI * *phase).sin()
+        };
+        let decay = |speed: f64| (-time * speed).exp();
+        match self {
+            Self::Kick => sweep(150.0, 50.0, 30.0) * decay(8.0),
+            Self::Snare => {
+                let tone = sweep(185.0, 185.0, 0.0) * decay(25.0);
+                0.6f64.mul_add(noise * decay(20.0), 0.4 * tone)
+            }
+            Self::ClosedHiHat => 0.5 * high_noise * decay(60.0),
+            Self::OpenHiHat => 0.5 * high_noise * decay(8.0),
+            Self::LowTom => sweep(130.0, 90.0, 10.0) * decay(7.0),
+            Self::HighTom => sweep(240.0, 180.0, 10.0) * decay(7.0),
+            Self::Crash => 0.5 * high_noise * decay(3.0),
+        }
+    }
+}
+
+/// 打楽器の音を合成した波形
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone)]
+pub struct DrumWave {
+    /// 鳴らす打楽器と、その音程のある成分の位相
+    drums: Vec<(Drum, f64)>,
+    amplitude: f32,
+    frame: u64,
+    lfsr: Lfsr,
+    last_noise: f64,
+}
+
+impl DrumWave {
+    pub fn new(drums: &[Drum], amplitude: f32) -> Self {
+        Self {
+            drums: drums.iter().map(|drum| (*drum, 0.0)).collect(),
+            amplitude,
+            frame: 0,
+            lfsr: Lfsr::new(),
+            last_noise: 0.0,
+        }
+    }
+}
+
+impl Source for DrumWave {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> NonZero<u16> {
+        const { NonZero::new(1).unwrap() }
+    }
+
+    fn sample_rate(&self) -> NonZero<u32> {
+        SAMPLE_RATE_NONZERO
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+impl Iterator for DrumWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let time = self.frame as f64 / SAMPLE_RATE as f64;
+        self.frame += 1;
+        self.lfsr.clock(false);
+        let noise = self.lfsr.value();
+        let high_noise = (noise - self.last_noise) / 2.0;
+        self.last_noise = noise;
+        let sum: f64 = self
+            .drums
+            .iter_mut()
+            .map(|(drum, phase)| drum.sample(time, phase, noise, high_noise))
+            .sum();
+        Some(sum as f32 * self.amplitude)
+    }
+}
+
+/// 音量の時間変化
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Envelope {
+    /// 鳴り始めが最大で、音が鳴り終わるまでに直線的に0になる
+    #[default]
+    Fadeout,
+    /// ADSRエンベロープ
+    Adsr {
+        /// 0から最大になるまでの時間(ミリ秒)
+        attack_ms: u32,
+        /// 最大からサステインレベルまで下がる時間(ミリ秒)
+        decay_ms: u32,
+        /// サステインレベル(最大に対する百分率)
+        sustain: u32,
+        /// 音を止めてから0になるまでの時間(ミリ秒)
+        release_ms: u32,
+    },
+}
+
+/// ミリ秒をフレーム数に変換する
+const fn ms_to_frames(ms: u32) -> u64 {
+    ms as u64 * SAMPLE_RATE as u64 / 1000
+}
+
+impl Envelope {
+    /// 音が鳴り始めてから`frame`フレーム目の音量(0～1)
+    ///
+    /// `gate`は音を止めるフレーム、`length`は次の音が始まるフレーム。
+    pub(crate) fn level(&self, frame: u64, gate: u64, length: u64) -> f32 {
+        match *self {
+            Self::Fadeout => {
+                if frame < gate {
+                    (gate - frame) as f32 / gate as f32
+                } else {
+                    0.0
+                }
+            }
+            Self::Adsr { release_ms, .. } => {
+                // リリースが次の音に重ならないように、音を止めるフレームを早める。
+                // ただし音の長さの半分より前には早めず、収まらない分はリリースを短くする。
+                let gate = gate.min(
+                    length
+                        .saturating_sub(ms_to_frames(release_ms))
+                        .max(length / 2),
+                );
+                let release = ms_to_frames(release_ms).min(length - gate);
+                if frame < gate {
+                    self.sustain_level(frame)
+                } else if frame - gate < release {
+                    self.sustain_level(gate) * (1.0 - (frame - gate) as f32 / release as f32)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// 音を止めない場合の`frame`フレーム目の音量
+    fn sustain_level(&self, frame: u64) -> f32 {
+        let Self::Adsr {
+            attack_ms,
+            decay_ms,
+            sustain,
+            ..
+        } = *self
+        else {
+            return 1.0;
+        };
+        let attack = ms_to_frames(attack_ms);
+        let decay = ms_to_frames(decay_ms);
+        let sustain = sustain.min(100) as f32 / 100.0;
+        if frame < attack {
+            frame as f32 / attack as f32
+        } else if frame - attack < decay {
+            (sustain - 1.0).mul_add((frame - attack) as f32 / decay as f32, 1.0)
+        } else {
+            sustain
+        }
+    }
+}
+
+/// 波形にエンベロープを適用し、指定された長さで打ち切る
+#[derive(Debug, Clone)]
+pub struct EnvelopeWave<S> {
+    source: S,
+    envelope: Envelope,
+    frame: u64,
+    gate: u64,
+    length: u64,
+}
+
+impl<S> EnvelopeWave<S> {
+    /// `sound_ms`ミリ秒の間音を鳴らし、`length_ms`ミリ秒で終了する波形を作る
+    pub const fn new(source: S, envelope: Envelope, sound_ms: u32, length_ms: u32) -> Self {
+        Self {
+            source,
+            envelope,
+            frame: 0,
+            gate: ms_to_frames(sound_ms),
+            length: ms_to_frames(length_ms),
+        }
+    }
+}
+
+impl<S: Source> Source for EnvelopeWave<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> NonZero<u16> {
+        const { NonZero::new(1).unwrap() }
+    }
+
+    fn sample_rate(&self) -> NonZero<u32> {
+        SAMPLE_RATE_NONZERO
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+impl<S: Source> Iterator for EnvelopeWave<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.frame >= self.length {
+            return None;
+        }
+        let level = self.envelope.level(self.frame, self.gate, self.length);
+        self.frame += 1;
+        Some(self.source.next()? * level)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ScoreWave {
-    Note(TakeDuration<NoteWave>),
-    Chord(TakeDuration<ChordWave>),
+    Note(EnvelopeWave<NoteWave>),
+    Chord(EnvelopeWave<ChordWave>),
+    Drum(EnvelopeWave<DrumWave>),
+    Fm(FmWave),
+    Sample(EnvelopeWave<SampleWave>),
     Rest(TakeDuration<Zero>),
 }
 
+impl ScoreWave {
+    /// 音を止めるフレームと次の音が始まるフレーム。休符なら`None`。
+    const fn timing(&self) -> Option<(u64, u64)> {
+        match self {
+            Self::Note(EnvelopeWave { gate, length, .. })
+            | Self::Chord(EnvelopeWave { gate, length, .. })
+            | Self::Drum(EnvelopeWave { gate, length, .. })
+            | Self::Fm(FmWave { gate, length, .. })
+            | Self::Sample(EnvelopeWave { gate, length, .. }) => Some((*gate, *length)),
+            Self::Rest(_) => None,
+        }
+    }
+}
+
 impl Source for ScoreWave {
     fn current_span_len(&self) -> Option<usize> {
         None
@@ -188,20 +982,87 @@ impl Iterator for ScoreWave {
         match self {
             Self::Note(note) => note.next(),
             Self::Chord(chord) => chord.next(),
+            Self::Drum(drum) => drum.next(),
+            Self::Fm(fm) => fm.next(),
+            Self::Sample(sample) => sample.next(),
             Self::Rest(rest) => rest.next(),
         }
     }
 }
 
+/// 左右の音量
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Pan {
+    pub left: f32,
+    pub right: f32,
+}
+
+impl Pan {
+    pub const CENTER: Self = Self {
+        left: 1.0,
+        right: 1.0,
+    };
+
+    /// 0が左端、64が中央、127が右端となる値から作る
+    ///
+    /// 中央では左右とも元の音量で鳴り、片側に寄せると反対側の音量だけが小さくなる。
+    pub fn new(pan: u32) -> Self {
+        let pan = pan.min(127) as f32;
+        Self {
+            left: ((127.0 - pan) / 63.0).min(1.0),
+            right: (pan / 64.0).min(1.0),
+        }
+    }
+}
+
+/// チャンネルの途中で変えられる設定
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ChannelSettings {
+    pub pan: Pan,
+    pub effects: Effects,
+    pub filter: Filter,
+    pub filter_envelope: FilterEnvelope,
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            pan: Pan::CENTER,
+            effects: Effects::default(),
+            filter: Filter::default(),
+            filter_envelope: FilterEnvelope::default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ChannelWave {
     waves: Vec<ScoreWave>,
+    /// 設定が変わる位置(`waves`の添字)と新しい設定
+    settings: Vec<(usize, ChannelSettings)>,
     index: usize,
+    /// 現在の音が鳴り始めてからのフレーム数
+    frame: u64,
+    filter: StateVariableFilter,
 }
 
 impl ChannelWave {
-    pub const fn new(waves: Vec<ScoreWave>) -> Self {
-        Self { waves, index: 0 }
+    pub const fn new(waves: Vec<ScoreWave>, settings: Vec<(usize, ChannelSettings)>) -> Self {
+        Self {
+            waves,
+            settings,
+            index: 0,
+            frame: 0,
+            filter: StateVariableFilter::new(),
+        }
+    }
+
+    /// 現在鳴っている音の設定
+    pub fn settings(&self) -> ChannelSettings {
+        let changes = self.settings.partition_point(|(i, _)| *i <= self.index);
+        changes
+            .checked_sub(1)
+            .map_or_else(ChannelSettings::default, |i| self.settings[i].1)
     }
 }
 
@@ -227,27 +1088,49 @@ impl Iterator for ChannelWave {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.waves.len() {
-            return None;
-        }
-
-        let wave = &mut self.waves[self.index];
-        match wave.next() {
-            Some(value) => Some(value),
-            None => {
+        loop {
+            let wave = self.waves.get_mut(self.index)?;
+            let timing = wave.timing();
+            let Some(value) = wave.next() else {
                 self.index += 1;
-                self.next()
-            }
+                self.frame = 0;
+                continue;
+            };
+            let settings = self.settings();
+            // 休符の間はカットオフ周波数を動かさない
+            let (gate, length) = timing.unwrap_or((0, 0));
+            let cutoff = settings.filter_envelope.cutoff(
+                settings.filter.cutoff_hz,
+                self.frame,
+                gate,
+                length,
+            );
+            self.frame += 1;
+            return Some(self.filter.process(value, &settings.filter, cutoff));
         }
     }
 }
 
+/// 全チャンネルを合成したステレオの波形
+///
+/// 各チャンネルにエフェクトをかけてから定位に従って左右に振り分け、
+/// 左と右のサンプルを交互に出力する。
 #[derive(Debug, Clone)]
-pub struct MusicWave(Vec<ChannelWave>);
+pub struct MusicWave {
+    channels: Vec<(ChannelWave, EffectChain)>,
+    /// 次に出力する右のサンプル
+    right: Option<f32>,
+}
 
 impl MusicWave {
-    pub const fn new(waves: Vec<ChannelWave>) -> Self {
-        Self(waves)
+    pub fn new(channels: Vec<ChannelWave>) -> Self {
+        Self {
+            channels: channels
+                .into_iter()
+                .map(|wave| (wave, EffectChain::new()))
+                .collect(),
+            right: None,
+        }
     }
 }
 
@@ -256,7 +1139,7 @@ impl Source for MusicWave {
         None
     }
     fn channels(&self) -> NonZero<u16> {
-        const { NonZero::new(1).unwrap() }
+        const { NonZero::new(2).unwrap() }
     }
     fn sample_rate(&self) -> NonZero<u32> {
         SAMPLE_RATE_NONZERO
@@ -269,9 +1152,394 @@ impl Source for MusicWave {
 impl Iterator for MusicWave {
     type Item = f32;
     fn next(&mut self) -> Option<Self::Item> {
-        let mut samples = self.0.iter_mut().filter_map(|wave| wave.next());
-        let first = samples.next()?;
-        let sum = samples.sum::<f32>() + first;
-        Some(sum)
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let mut frame = None;
+        for (wave, chain) in self.channels.iter_mut() {
+            // 次の音に移ったときは、その音の設定を使う
+            let input = wave.next();
+            let settings = wave.settings();
+            if let Some(sample) = chain.process(input, &settings.effects) {
+                let (left, right) = frame.get_or_insert((0.0, 0.0));
+                *left += sample * settings.pan.left;
+                *right += sample * settings.pan.right;
+            }
+        }
+        let (left, right) = frame?;
+        self.right = Some(right);
+        Some(left)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1秒分の波形を生成し、平均値を下から上に横切る間隔から周波数を求める
+    fn measure_frequency(waveform: Waveform, frequency: f32) -> f64 {
+        // 三角波の積分が安定するまで待つ
+        let skip = SAMPLE_RATE as usize / 10;
+        let samples: Vec<f32> = NoteWave::new(waveform, frequency, 1.0)
+            .take(SAMPLE_RATE as usize)
+            .collect();
+        let mean = samples[skip..].iter().sum::<f32>() / (samples.len() - skip) as f32;
+        let samples: Vec<f32> = samples.iter().map(|s| s - mean).collect();
+        let crossings: Vec<f64> = samples
+            .windows(2)
+            .enumerate()
+            .skip(skip)
+            .filter(|(_, w)| w[0] < 0.0 && w[1] >= 0.0)
+            .map(|(i, w)| i as f64 + (-w[0] / (w[1] - w[0])) as f64)
+            .collect();
+        let periods = (crossings.len() - 1) as f64;
+        periods * SAMPLE_RATE as f64 / (crossings.last().unwrap() - crossings[0])
+    }
+
+    #[test]
+    fn pitch_accuracy() {
+        for waveform in [
+            Waveform::Saw,
+            Waveform::Square(0.5),
+            Waveform::Square(0.1),
+            Waveform::Triangle,
+            Waveform::Sine,
+            Waveform::Wavetable(vec![-1.0, -0.5, 0.0, 0.5, 1.0, 0.5, 0.0, -0.5]),
+        ] {
+            for n in [21, 45, 60, 69, 81, 96, 108, 120] {
+                let expected = crate::ast::notenumber_to_hz(n);
+                let measured = measure_frequency(waveform.clone(), expected);
+                let error = (measured / expected as f64 - 1.0).abs();
+                assert!(
+                    error < 1e-3,
+                    "{:?} n{}: {} Hz (expected {} Hz)",
+                    waveform,
+                    n,
+                    measured,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn amplitude() {
+        for waveform in [
+            Waveform::Saw,
+            Waveform::Square(0.5),
+            Waveform::Triangle,
+            Waveform::Sine,
+        ] {
+            let peak = NoteWave::new(waveform.clone(), 440.0, 0.5)
+                .skip(SAMPLE_RATE as usize / 10)
+                .take(1000)
+                .fold(0.0, |peak: f32, s| peak.max(s.abs()));
+            assert!((0.45..0.55).contains(&peak), "{:?}: {}", waveform, peak);
+        }
+    }
+
+    #[test]
+    fn band_limited() {
+        // 帯域制限されていれば不連続な変化が1サンプルで起こらない
+        for waveform in [
+            Waveform::Saw,
+            Waveform::Square(0.5),
+            Waveform::Wavetable(vec![1.0, -1.0]),
+        ] {
+            let samples: Vec<f32> = NoteWave::new(waveform.clone(), 3000.0, 1.0)
+                .take(1000)
+                .collect();
+            let max_step = samples
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0.0, f32::max);
+            assert!(max_step < 1.9, "{:?}: {}", waveform, max_step);
+        }
+    }
+
+    #[test]
+    fn noise() {
+        // 1サンプルごとにレジスタが1ステップ進む周波数
+        let frequency = (SAMPLE_RATE as f64 / NOISE_CLOCK_RATIO) as f32;
+        let samples: Vec<f32> = NoteWave::new(Waveform::PeriodicNoise, frequency, 1.0)
+            .take(1000)
+            .collect();
+        assert!(samples.iter().all(|s| s.abs() == 1.0));
+        assert_eq!(samples[..907], samples[93..]);
+
+        let samples: Vec<f32> = NoteWave::new(Waveform::Noise, frequency, 1.0)
+            .take(32767)
+            .collect();
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!(mean.abs() < 0.01, "{}", mean);
+        assert_ne!(samples[..907], samples[93..1000]);
+    }
+
+    #[test]
+    fn drum_decays() {
+        for drum in [Drum::Kick, Drum::Snare, Drum::ClosedHiHat, Drum::Crash] {
+            let peak = |samples: &[f32]| samples.iter().fold(0.0, |peak: f32, s| peak.max(s.abs()));
+            let samples: Vec<f32> = DrumWave::new(&[drum], 1.0)
+                .take(SAMPLE_RATE as usize * 2)
+                .collect();
+            let start = peak(&samples[..SAMPLE_RATE as usize / 10]);
+            let end = peak(&samples[SAMPLE_RATE as usize * 19 / 10..]);
+            assert!(start > 0.1 && end < 0.01, "{:?}: {} {}", drum, start, end);
+        }
+    }
+
+    #[test]
+    fn fm() {
+        let flat = Envelope::Adsr {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain: 100,
+            release_ms: 0,
+        };
+        let voice = |levels: [u32; 2], ratio: u32| FmVoice {
+            algorithm: 0,
+            feedback: 0,
+            operators: vec![
+                FmOperator {
+                    ratio: 1,
+                    level: levels[0],
+                    envelope: flat,
+                },
+                FmOperator {
+                    ratio,
+                    level: levels[1],
+                    envelope: flat,
+                },
+            ],
+        };
+        let wave = |voice| FmWave::new(voice, &[440.0], 1.0, 1000, 1000).collect::<Vec<f32>>();
+
+        // 変調しなければキャリアの正弦波になる
+        let samples = wave(voice([0, 100], 2));
+        assert_eq!(samples.len(), SAMPLE_RATE as usize);
+        let sine: Vec<f32> = NoteWave::new(Waveform::Sine, 880.0, 1.0)
+            .take(samples.len())
+            .collect();
+        assert!(samples.iter().zip(&sine).all(|(a, b)| (a - b).abs() < 1e-4));
+
+        // 変調すると正弦波から外れるが、振幅はキャリアのレベルを超えない
+        let samples = wave(voice([100, 50], 1));
+        assert!(samples.iter().zip(&sine).any(|(a, b)| (a - b).abs() > 0.1));
+        assert!(samples.iter().all(|s| s.abs() <= 0.5));
+
+        let mut invalid = voice([0, 100], 1);
+        invalid.algorithm = 2;
+        assert!(!invalid.is_valid());
+    }
+
+    #[test]
+    fn sample_wave() {
+        let data = SampleData {
+            frames: Arc::from([0.0, 1.0, 2.0, 3.0]),
+            sample_rate: SAMPLE_RATE,
+        };
+        let instrument = |loop_frames| SampleInstrument {
+            data: data.clone(),
+            root: 60,
+            loop_frames,
+        };
+        let c4 = crate::ast::notenumber_to_hz(60);
+        let c5 = crate::ast::notenumber_to_hz(72);
+        let g4 = crate::ast::notenumber_to_hz(67);
+        let play = |wave: SampleWave| wave.take(6).collect::<Vec<f32>>();
+
+        assert_eq!(
+            play(SampleWave::new(instrument(None), &[c4], 1.0)),
+            [0.0, 1.0, 2.0, 3.0, 0.0, 0.0]
+        );
+        // 1オクターブ上では2倍の速さで再生する
+        let octave = play(SampleWave::new(instrument(None), &[c5], 1.0));
+        assert!((octave[1] - 2.0).abs() < 1e-3 && octave[2] == 0.0);
+        // 間のフレームは補間される
+        let fifth = play(SampleWave::new(instrument(None), &[g4], 1.0));
+        assert!((fifth[1] - 1.498).abs() < 1e-2);
+        assert_eq!(
+            play(SampleWave::new(instrument(Some((1, 3))), &[c4], 1.0)),
+            [0.0, 1.0, 2.0, 1.0, 2.0, 1.0]
+        );
+        // ループより速く進んでもループの外には出ない
+        let fast = SampleWave::new(instrument(Some((1, 2))), &[c4 * 3.0], 1.0);
+        assert!(
+            fast.skip(1)
+                .take(20)
+                .all(|frame| (1.0..=2.0).contains(&frame))
+        );
+        assert_eq!(
+            play(SampleWave::new(instrument(None), &[c4, c4], 0.5)),
+            [0.0, 1.0, 2.0, 3.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn sample_from_wav() {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = io::Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for sample in [i16::MIN, 0, 16384, 16384] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let data = SampleData::from_wav(wav.get_ref().as_slice()).unwrap();
+        assert_eq!(data.sample_rate, 22050);
+        assert_eq!(*data.frames, [-0.5, 0.5]);
+        assert!(SampleData::from_wav(&b"not a wav file"[..]).is_err());
+    }
+
+    #[test]
+    fn stereo() {
+        let note = |frequency| {
+            ScoreWave::Note(EnvelopeWave::new(
+                NoteWave::new(Waveform::Square(0.5), frequency, 1.0),
+                Envelope::Adsr {
+                    attack_ms: 0,
+                    decay_ms: 0,
+                    sustain: 100,
+                    release_ms: 0,
+                },
+                10,
+                10,
+            ))
+        };
+        let pan = |p| ChannelSettings {
+            pan: Pan::new(p),
+            ..Default::default()
+        };
+        let music = MusicWave::new(vec![
+            ChannelWave::new(vec![note(100.0), note(100.0)], vec![(1, pan(0))]),
+            ChannelWave::new(vec![note(100.0)], vec![(0, pan(127))]),
+        ]);
+        assert_eq!(music.channels().get(), 2);
+        let samples: Vec<f32> = music.collect();
+        // 10ms = 441フレーム
+        assert_eq!(samples.len(), 441 * 2 * 2);
+        let (first, second) = samples.split_at(441 * 2);
+        assert!((first[2] - 1.0).abs() < 1e-3 && (first[3] - 2.0).abs() < 1e-3);
+        assert!((second[2] - 1.0).abs() < 1e-3 && second[3].abs() < 1e-3);
+
+        // 設定が変わった最初のフレームから新しい設定で鳴る
+        let constant = |frames| {
+            ScoreWave::Note(EnvelopeWave::new(
+                NoteWave::new(Waveform::Wavetable(vec![1.0, 1.0]), 100.0, 1.0),
+                Envelope::Fadeout,
+                frames,
+                frames,
+            ))
+        };
+        let music = MusicWave::new(vec![ChannelWave::new(
+            vec![constant(10), constant(10)],
+            vec![(0, pan(127)), (1, pan(0))],
+        )]);
+        let samples: Vec<f32> = music.collect();
+        assert!(samples[441 * 2] > 0.5);
+        assert!(samples[441 * 2 + 1].abs() < 1e-3);
+
+        assert_eq!(Pan::new(64), Pan::CENTER);
+    }
+
+    #[test]
+    fn vibrato() {
+        let vibrato = Vibrato {
+            depth: 100,
+            rate: 10,
+            delay_ms: 500,
+        };
+        let samples: Vec<f32> = NoteWave::new(Waveform::Sine, 440.0, 1.0)
+            .with_vibrato(vibrato)
+            .take(SAMPLE_RATE as usize * 2)
+            .collect();
+        // 0.25秒ごとの区間の周期の数
+        let cycles: Vec<usize> = samples
+            .chunks(SAMPLE_RATE as usize / 4)
+            .map(|c| c.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count())
+            .collect();
+        // 揺れ始めるまでは元の高さ、その後は1秒周期で最大1半音上下する
+        assert!((109..=111).contains(&cycles[1]), "{:?}", cycles);
+        assert!(cycles[2] > 113 && cycles[2] < 117, "{:?}", cycles);
+        assert!(cycles[4] < 107 && cycles[4] > 103, "{:?}", cycles);
+
+        assert_eq!(Vibrato::default().ratio(1000), 1.0);
+        assert_eq!(vibrato.ratio(0), 1.0);
+    }
+
+    #[test]
+    fn slide() {
+        let samples: Vec<f32> = NoteWave::new(Waveform::Sine, 880.0, 1.0)
+            .with_slide(440.0, 500)
+            .take(SAMPLE_RATE as usize)
+            .collect();
+        let cycles: Vec<usize> = samples
+            .chunks(SAMPLE_RATE as usize / 10)
+            .map(|c| c.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count())
+            .collect();
+        // 0.5秒かけて440Hzから880Hzまで上がり、その後は880Hzのまま
+        assert!((44..=47).contains(&cycles[0]), "{:?}", cycles);
+        assert!(
+            cycles.windows(2).take(5).all(|w| w[0] < w[1]),
+            "{:?}",
+            cycles
+        );
+        assert!(
+            cycles[5..].iter().all(|c| (87..=89).contains(c)),
+            "{:?}",
+            cycles
+        );
+    }
+
+    #[test]
+    fn envelope_level() {
+        let fadeout = Envelope::Fadeout;
+        assert_eq!(fadeout.level(0, 100, 200), 1.0);
+        assert_eq!(fadeout.level(50, 100, 200), 0.5);
+        assert_eq!(fadeout.level(100, 100, 200), 0.0);
+
+        // 10ms = 441フレーム、20ms = 882フレーム
+        let adsr = Envelope::Adsr {
+            attack_ms: 10,
+            decay_ms: 20,
+            sustain: 50,
+            release_ms: 20,
+        };
+        let length = 44100;
+        assert_eq!(adsr.level(0, 22050, length), 0.0);
+        assert_eq!(adsr.level(441, 22050, length), 1.0);
+        assert_eq!(adsr.level(441 + 441, 22050, length), 0.75);
+        assert_eq!(adsr.level(10000, 22050, length), 0.5);
+        assert_eq!(adsr.level(22050 + 441, 22050, length), 0.25);
+        assert_eq!(adsr.level(22050 + 882, 22050, length), 0.0);
+        // 音を止めるのが遅い場合はリリースが終わるまでに次の音が始まらないよう早める
+        assert_eq!(adsr.level(length - 882, length, length), 0.5);
+        assert_eq!(adsr.level(length - 441, length, length), 0.25);
+        // リリースより短い音でも鳴り、次の音が始まるまでにリリースが終わる
+        let long_release = Envelope::Adsr {
+            attack_ms: 10,
+            decay_ms: 0,
+            sustain: 100,
+            release_ms: 200,
+        };
+        let short = 1764;
+        assert_eq!(long_release.level(441, short, short), 1.0);
+        assert_eq!(long_release.level(882, short, short), 1.0);
+        assert_eq!(long_release.level(882 + 441, short, short), 0.5);
+        assert_eq!(long_release.level(short, short, short), 0.0);
+        // 音を止めるのが早い場合はリリースを短くしない
+        assert_eq!(long_release.level(4410 + 4410, 4410, length), 0.5);
+    }
+
+    #[test]
+    fn envelope_wave_length() {
+        let source = NoteWave::new(Waveform::Sine, 440.0, 1.0);
+        let wave = EnvelopeWave::new(source, Envelope::Fadeout, 50, 100);
+        assert_eq!(wave.count(), 4410);
     }
 }
@@ -0,0 +1,362 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ast::{LmmlAst, LmmlCommand, resolve_length};
+
+/// コマンドを表す文字の大文字・小文字
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum LetterCase {
+    #[default]
+    Lower,
+    Upper,
+}
+
+/// [`LmmlAst`]をLMMLのソースコードに変換するときの設定
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, Clone)]
+pub struct PrintOptions {
+    pub case: LetterCase,
+    /// 1行の最大文字数。`None`なら`:`コマンド以外では改行しない。
+    ///
+    /// 1つのコマンドがこれより長い場合はその行だけ超過する。
+    pub line_width: Option<usize>,
+    /// `true`ならコマンドをチャンネルごとにまとめて出力する
+    ///
+    /// チャンネルごとのコマンドの順序は保たれるため演奏結果は変わらないが、
+    /// 再びパースしたときのASTは元のASTと一致しない。
+    /// ループやマクロの中でチャンネルを変更している場合など、
+    /// 並べ替えると演奏結果が変わる場合はまとめない。
+    pub group_by_channel: bool,
+    /// 1小節の長さ(四分音符の数)
+    ///
+    /// `Some`なら同じ小節内の音符・休符を空白を入れずに続けて書き、小節の区切りに空白を入れる。
+    /// `None`なら全てのコマンドを空白で区切る。
+    pub bar_length: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BarState {
+    length: u32,
+    is_dotted: bool,
+    /// 小節の先頭からの位置(全音符単位)
+    position: f64,
+}
+
+impl Default for BarState {
+    fn default() -> Self {
+        Self {
+            length: 4,
+            is_dotted: false,
+            position: 0.0,
+        }
+    }
+}
+
+/// 音符・休符の長さ(全音符単位)
+fn whole_notes(state: &BarState, length: Option<u32>, is_dotted: bool) -> f64 {
+    let (length, is_dotted) = resolve_length(state.length, state.is_dotted, length, is_dotted);
+    if length == 0 {
+        return 0.0;
+    }
+    let dot = if is_dotted { 1.5 } else { 1.0 };
+    dot / length as f64
+}
+
+/// 全てのチャンネルで共有される定義の名前
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SharedName<'c> {
+    Macro(&'c str),
+    Envelope(u32),
+    Instrument(u32),
+}
+
+/// `command`を評価したときに定義される共有の名前を`names`に加える
+///
+/// `macros`はそれまでに定義されたマクロ、`stack`は展開中のマクロ。
+fn shared_names<'c>(
+    command: &'c LmmlCommand,
+    macros: &BTreeMap<&'c str, &'c [LmmlCommand]>,
+    stack: &mut Vec<&'c str>,
+    names: &mut Vec<SharedName<'c>>,
+) {
+    match command {
+        LmmlCommand::DefineMacro { name, .. } => names.push(SharedName::Macro(name)),
+        LmmlCommand::DefineEnvelope { number, .. } => names.push(SharedName::Envelope(*number)),
+        LmmlCommand::DefineWavetable { number, .. }
+        | LmmlCommand::DefineFm { number, .. }
+        | LmmlCommand::DefineSample { number, .. } => names.push(SharedName::Instrument(*number)),
+        LmmlCommand::Loop { body, count } => {
+            // 繰り返すたびに定義し直される
+            for _ in 0..count.unwrap_or(2).min(2) {
+                for command in body {
+                    shared_names(command, macros, stack, names);
+                }
+            }
+        }
+        LmmlCommand::CallMacro(name) if !stack.contains(&name.as_str()) => {
+            if let Some((name, body)) = macros.get_key_value(name.as_str()) {
+                stack.push(name);
+                for command in body.iter() {
+                    shared_names(command, macros, stack, names);
+                }
+                stack.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// コマンドをチャンネルごとにまとめて並べ替えても演奏結果が変わらないかどうか
+pub fn can_group_by_channel<'c>(commands: impl IntoIterator<Item = &'c LmmlCommand>) -> bool {
+    let mut current = 0;
+    let mut first = None;
+    // 最初のチャンネル以外のコマンドがあったかどうか
+    let mut other_channel = false;
+    let mut macros = BTreeMap::new();
+    let mut defined = BTreeSet::new();
+    for command in commands {
+        match command {
+            LmmlCommand::SetChannel(n) => current = *n,
+            // 読み込むファイルの内容や、ループやマクロの中でのチャンネルの変更は追跡できない
+            LmmlCommand::Include(_) => return false,
+            LmmlCommand::Loop { body, .. } | LmmlCommand::DefineMacro { body, .. }
+                if body.iter().any(LmmlCommand::sets_channel) =>
+            {
+                return false;
+            }
+            _ => {}
+        }
+        let first = *first.get_or_insert(current);
+
+        // マクロなどの定義は全てのチャンネルに影響するため、
+        // 他のチャンネルのコマンドより前の、最初のチャンネルの中でしか定義できない。
+        // 定義し直すと、まとめたときに定義し直す前の定義を使うはずのコマンドより前に移動してしまう。
+        let mut names = Vec::new();
+        shared_names(command, &macros, &mut Vec::new(), &mut names);
+        if !names.is_empty() && (current != first || other_channel) {
+            return false;
+        }
+        for name in names {
+            if !defined.insert(name) {
+                return false;
+            }
+        }
+
+        if let LmmlCommand::DefineMacro { name, body } = command {
+            macros.insert(name.as_str(), body.as_slice());
+        }
+        if current != first && !matches!(command, LmmlCommand::SetChannel(_)) {
+            other_channel = true;
+        }
+    }
+    true
+}
+
+/// LMMLのソースコードを組み立てる
+///
+/// [`LmmlAst::to_lmml`]の他、コメントや空行を保ちながら整形する場合にも使用する。
+pub struct Printer<'a> {
+    options: &'a PrintOptions,
+    output: String,
+    line_len: usize,
+    /// 直前のコマンドに空白を入れずに続けて書けるかどうか
+    glue: bool,
+    channel: u32,
+    bars: BTreeMap<u32, BarState>,
+}
+
+impl<'a> Printer<'a> {
+    pub const fn new(options: &'a PrintOptions) -> Self {
+        Self {
+            options,
+            output: String::new(),
+            line_len: 0,
+            glue: false,
+            channel: 0,
+            bars: BTreeMap::new(),
+        }
+    }
+
+    /// 現在の行を終える
+    pub fn end_line(&mut self) {
+        if self.line_len != 0 {
+            self.output.push('\n');
+            self.line_len = 0;
+        }
+        self.glue = false;
+    }
+
+    /// 空行を入れる。連続した空行と先頭の空行は1つにまとめられる。
+    pub fn blank_line(&mut self) {
+        self.end_line();
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push('\n');
+        }
+    }
+
+    /// コメントを1行として出力する
+    pub fn comment(&mut self, comment: &str) {
+        self.verbatim(comment);
+    }
+
+    /// `line`を整形せずに1行として出力する
+    pub fn verbatim(&mut self, line: &str) {
+        self.end_line();
+        self.output.push_str(line);
+        self.output.push('\n');
+    }
+
+    fn word(&mut self, word: &str, glue: bool) {
+        let len = word.chars().count();
+        if self.line_len != 0 {
+            if self
+                .options
+                .line_width
+                .is_some_and(|width| self.line_len + 1 + len > width)
+            {
+                self.end_line();
+            } else if !glue {
+                self.output.push(' ');
+                self.line_len += 1;
+            }
+        }
+        self.output.push_str(word);
+        self.line_len += len;
+    }
+
+    pub fn command(&mut self, command: &LmmlCommand) {
+        if let LmmlCommand::Loop { body, count } = command {
+            self.word("/:", false);
+            self.glue = false;
+            self.commands(body);
+            let count = count.map(|n| n.to_string()).unwrap_or_default();
+            self.word(&format!(":/{}", count), false);
+            self.glue = false;
+            return;
+        }
+        // マクロの名前やファイル名は大文字・小文字を区別するため変換しない
+        if let LmmlCommand::DefineMacro { name, body } = command {
+            self.word(&format!("!{} =", name), false);
+            self.glue = false;
+            self.commands(body);
+            // 行頭の`;`はコメントとみなされるため、直前の行に続けて書く
+            self.output.push(';');
+            self.line_len += 1;
+            self.glue = false;
+            return;
+        }
+        if let LmmlCommand::CallMacro(_)
+        | LmmlCommand::Include(_)
+        | LmmlCommand::DefineSample { .. } = command
+        {
+            self.word(&command.to_string(), false);
+            self.glue = false;
+            return;
+        }
+        if let LmmlCommand::SetChannel(n) = command {
+            self.end_line();
+            self.channel = *n;
+        }
+
+        let text = command.to_string();
+        let text = match self.options.case {
+            LetterCase::Lower => text,
+            LetterCase::Upper => text.to_uppercase(),
+        };
+
+        let Some(bar_length) = self.options.bar_length else {
+            self.word(&text, false);
+            return;
+        };
+
+        let gluable = matches!(
+            command,
+            LmmlCommand::Note { .. }
+                | LmmlCommand::Rest { .. }
+                | LmmlCommand::Chord { .. }
+                | LmmlCommand::NoteNumber(_)
+                | LmmlCommand::IncreaseOctave
+                | LmmlCommand::DecreaseOctave
+                | LmmlCommand::Tie
+                | LmmlCommand::Slide
+                | LmmlCommand::TieLength { .. }
+        );
+        self.word(&text, self.glue && gluable);
+        self.glue = gluable;
+
+        let bar = self.bars.entry(self.channel).or_default();
+        let duration = match command {
+            LmmlCommand::Note {
+                length, is_dotted, ..
+            }
+            | LmmlCommand::Rest { length, is_dotted }
+            | LmmlCommand::Chord {
+                length, is_dotted, ..
+            }
+            | LmmlCommand::TieLength { length, is_dotted } => whole_notes(bar, *length, *is_dotted),
+            LmmlCommand::NoteNumber(_) => whole_notes(bar, None, false),
+            LmmlCommand::SetLength(l, d) => {
+                bar.length = *l;
+                bar.is_dotted = *d;
+                0.0
+            }
+            _ => 0.0,
+        };
+        bar.position += duration;
+        let bar_whole_notes = bar_length as f64 / 4.0;
+        if bar_length != 0 && bar.position >= bar_whole_notes - 1e-9 {
+            bar.position = (bar.position - bar_whole_notes).max(0.0) % bar_whole_notes;
+            self.glue = false;
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.output.trim_end().to_string()
+    }
+
+    fn commands<'c>(&mut self, commands: impl IntoIterator<Item = &'c LmmlCommand>) {
+        for command in commands {
+            self.command(command);
+        }
+    }
+
+    fn grouped(&mut self, commands: &[LmmlCommand]) {
+        if !can_group_by_channel(commands) {
+            self.commands(commands);
+            return;
+        }
+
+        let mut channels: Vec<(u32, Vec<&LmmlCommand>)> = Vec::new();
+        let mut current = 0;
+        for command in commands {
+            if let LmmlCommand::SetChannel(n) = command {
+                current = *n;
+                continue;
+            }
+            match channels.iter_mut().find(|(n, _)| *n == current) {
+                Some((_, v)) => v.push(command),
+                None => channels.push((current, vec![command])),
+            }
+        }
+
+        for (n, commands) in channels {
+            self.blank_line();
+            self.commands(std::iter::once(&LmmlCommand::SetChannel(n)).chain(commands));
+        }
+    }
+}
+
+impl LmmlAst {
+    /// LMMLのソースコードに変換する
+    ///
+    /// `options.group_by_channel`が`false`であれば、出力をパースすると元のASTと同じものが得られる。
+    pub fn to_lmml(&self, options: &PrintOptions) -> String {
+        let mut printer = Printer::new(options);
+        if options.group_by_channel {
+            printer.grouped(&self.0);
+        } else {
+            printer.commands(&self.0);
+        }
+        printer.finish()
+    }
+}
@@ -1,9 +1,15 @@
-use std::{fmt::Display, num::NonZero, time::Duration};
+use std::{fmt::Display, num::NonZero, sync::Arc, time::Duration};
 
 use rodio::{Player, Source};
 
-use crate::oscillator::{
-    ChannelWave, ChordWave, MusicWave, NoteWave, SAMPLE_RATE_NONZERO, ScoreWave, Waveform,
+use crate::{
+    effect::{Chorus, Delay, Reverb},
+    filter::{Filter, FilterEnvelope},
+    oscillator::{
+        ChannelSettings, ChannelWave, ChordWave, Drum, DrumWave, Envelope, EnvelopeWave, FmVoice,
+        FmWave, MusicWave, NoteWave, Pan, SAMPLE_RATE_NONZERO, SampleInstrument, SampleWave,
+        ScoreWave, Vibrato, Waveform,
+    },
 };
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -12,6 +18,39 @@ pub struct LmmlTimeline {
     pub timeline: [Vec<Element>; 16],
 }
 
+/// `@`コマンドで選択できるユーザー定義の音色
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
+pub enum Instrument {
+    /// 1周期分の波形。値は-1～1。
+    Wavetable(Vec<f32>),
+    /// FM音源
+    Fm(FmVoice),
+    /// 音声ファイル
+    Sample(SampleInstrument),
+}
+
+impl Instrument {
+    /// 数値の列から波形の音色を作る
+    ///
+    /// 最小値が-1、最大値が1になるように変換する。全て同じ値なら無音になる。
+    pub fn wavetable(samples: &[u32]) -> Self {
+        let min = samples.iter().copied().min().unwrap_or(0);
+        let max = samples.iter().copied().max().unwrap_or(0);
+        let samples = samples
+            .iter()
+            .map(|s| {
+                if max == min {
+                    0.0
+                } else {
+                    2.0f32.mul_add((s - min) as f32 / (max - min) as f32, -1.0)
+                }
+            })
+            .collect();
+        Self::Wavetable(samples)
+    }
+}
+
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Clone)]
 pub enum Element {
@@ -22,7 +61,10 @@ pub enum Element {
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Clone)]
 pub struct Note {
+    /// 次の音が始まるまでの長さ
     pub length_ms: u32,
+    /// 音が鳴っている長さ。`length_ms`以下で、残りの時間は無音になる。
+    pub sound_ms: u32,
     pub note_type: NoteType,
 }
 
@@ -33,24 +75,107 @@ pub enum NoteType {
         hz: f32,
         volume: f32,
         waveform: u32,
+        /// `waveform`がユーザー定義の音色を指す場合、音を置いた時点でのその音色
+        instrument: Option<Arc<Instrument>>,
+        envelope: Envelope,
+        vibrato: Vibrato,
+        /// 直前の音の高さから`hz`まで滑らかに変える場合の設定
+        slide: Option<Slide>,
     },
     Chord {
         hzs: Vec<f32>,
         volume: f32,
         waveform: u32,
+        /// `waveform`がユーザー定義の音色を指す場合、音を置いた時点でのその音色
+        instrument: Option<Arc<Instrument>>,
+        envelope: Envelope,
+        vibrato: Vibrato,
+    },
+    /// 打楽器。同時に鳴らす打楽器を持つ。
+    Drum {
+        drums: Vec<Drum>,
+        volume: f32,
+        envelope: Envelope,
     },
     Rest,
 }
 
+/// ポルタメント
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Slide {
+    /// 鳴り始めの周波数
+    pub from_hz: f32,
+    /// 本来の高さに達するまでの時間(ミリ秒)
+    pub time_ms: u32,
+}
+
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Event {
     ChangeTempo(u32),
+    /// 左右の定位を変える。0が左端、64が中央、127が右端。
+    ChangePan(u32),
+    /// ディレイの設定を変える
+    ChangeDelay(Delay),
+    /// リバーブの設定を変える
+    ChangeReverb(Reverb),
+    /// コーラスの設定を変える
+    ChangeChorus(Chorus),
+    /// フィルターの種類・カットオフ周波数・レゾナンスを変える
+    ChangeFilter(Filter),
+    /// 音符ごとにカットオフ周波数を動かすエンベロープを変える
+    ChangeFilterEnvelope(FilterEnvelope),
 }
 
 impl LmmlTimeline {
+    /// `@`コマンドの番号に対応する波形
+    fn waveform(number: u32, instrument: Option<&Instrument>) -> Waveform {
+        match number {
+            1 => Waveform::Square(0.5),
+            2 => Waveform::Square(0.1),
+            3 => Waveform::Triangle,
+            4 => Waveform::Sine,
+            5 => Waveform::Noise,
+            6 => Waveform::PeriodicNoise,
+            _ => match instrument {
+                Some(Instrument::Wavetable(table)) => Waveform::Wavetable(table.clone()),
+                Some(Instrument::Fm(_) | Instrument::Sample(_)) | None => Waveform::Saw,
+            },
+        }
+    }
+
+    /// FM音源や音声ファイルの音色の波形。それ以外の音色なら`None`を返す。
+    fn instrument_wave(
+        note: &Note,
+        hzs: &[f32],
+        volume: f32,
+        instrument: Option<&Instrument>,
+        envelope: Envelope,
+    ) -> Option<ScoreWave> {
+        match instrument? {
+            // FM音源の音色は各オペレーターのエンベロープを使う
+            Instrument::Fm(voice) => Some(ScoreWave::Fm(FmWave::new(
+                voice.clone(),
+                hzs,
+                0.01 * volume,
+                note.sound_ms,
+                note.length_ms,
+            ))),
+            Instrument::Sample(sample) => Some(ScoreWave::Sample(EnvelopeWave::new(
+                SampleWave::new(sample.clone(), hzs, 0.01 * volume),
+                envelope,
+                note.sound_ms,
+                note.length_ms,
+            ))),
+            Instrument::Wavetable(_) => None,
+        }
+    }
+
     fn generate_channel_wave(&self, i: usize) -> ChannelWave {
         let mut waves = vec![];
+        let mut settings = ChannelSettings::default();
+        let mut changes = vec![];
         for element in self.timeline[i].iter() {
             match element {
                 Element::Note(note) => match note.note_type {
@@ -58,40 +183,73 @@ impl LmmlTimeline {
                         hz,
                         volume,
                         waveform,
+                        ref instrument,
+                        envelope,
+                        vibrato,
+                        slide,
                     } => {
-                        let waveform = match waveform {
-                            1 => Waveform::Square(0.5),
-                            2 => Waveform::Square(0.1),
-                            3 => Waveform::Triangle,
-                            4 => Waveform::Sine,
-                            _ => Waveform::Saw,
-                        };
-
-                        let mut source = NoteWave::new(waveform, hz, 0.01 * volume)
-                            .take_duration(Duration::from_millis(note.length_ms as u64));
-                        source.set_filter_fadeout();
-                        waves.push(ScoreWave::Note(source));
+                        let instrument = instrument.as_deref();
+                        if let Some(wave) =
+                            Self::instrument_wave(note, &[hz], volume, instrument, envelope)
+                        {
+                            waves.push(wave);
+                            continue;
+                        }
+                        let waveform = Self::waveform(waveform, instrument);
+                        let mut source =
+                            NoteWave::new(waveform, hz, 0.01 * volume).with_vibrato(vibrato);
+                        if let Some(slide) = slide {
+                            source = source.with_slide(slide.from_hz, slide.time_ms);
+                        }
+                        waves.push(ScoreWave::Note(EnvelopeWave::new(
+                            source,
+                            envelope,
+                            note.sound_ms,
+                            note.length_ms,
+                        )));
                     }
                     NoteType::Chord {
                         ref hzs,
                         volume,
                         waveform,
+                        ref instrument,
+                        envelope,
+                        vibrato,
                     } => {
-                        let waveform = match waveform {
-                            1 => Waveform::Square(0.5),
-                            2 => Waveform::Square(0.1),
-                            3 => Waveform::Triangle,
-                            4 => Waveform::Sine,
-                            _ => Waveform::Saw,
-                        };
-                        let mut source = ChordWave::new(
+                        let instrument = instrument.as_deref();
+                        if let Some(wave) =
+                            Self::instrument_wave(note, hzs, volume, instrument, envelope)
+                        {
+                            waves.push(wave);
+                            continue;
+                        }
+                        let waveform = Self::waveform(waveform, instrument);
+                        let source = ChordWave::new(
                             hzs.iter()
-                                .map(|hz| NoteWave::new(waveform, *hz, 0.01 * volume))
+                                .map(|hz| {
+                                    NoteWave::new(waveform.clone(), *hz, 0.01 * volume)
+                                        .with_vibrato(vibrato)
+                                })
                                 .collect(),
-                        )
-                        .take_duration(Duration::from_millis(note.length_ms as u64));
-                        source.set_filter_fadeout();
-                        waves.push(ScoreWave::Chord(source))
+                        );
+                        waves.push(ScoreWave::Chord(EnvelopeWave::new(
+                            source,
+                            envelope,
+                            note.sound_ms,
+                            note.length_ms,
+                        )));
+                    }
+                    NoteType::Drum {
+                        ref drums,
+                        volume,
+                        envelope,
+                    } => {
+                        waves.push(ScoreWave::Drum(EnvelopeWave::new(
+                            DrumWave::new(drums, 0.01 * volume),
+                            envelope,
+                            note.sound_ms,
+                            note.length_ms,
+                        )));
                     }
                     NoteType::Rest => {
                         let source = rodio::source::Zero::new(
@@ -104,16 +262,47 @@ impl LmmlTimeline {
                 },
                 Element::Event(event) => match event {
                     Event::ChangeTempo(_) => { /* do nothing */ }
+                    Event::ChangePan(pan) => {
+                        settings.pan = Pan::new(*pan);
+                        changes.push((waves.len(), settings));
+                    }
+                    Event::ChangeDelay(delay) => {
+                        settings.effects.delay = *delay;
+                        changes.push((waves.len(), settings));
+                    }
+                    Event::ChangeReverb(reverb) => {
+                        settings.effects.reverb = *reverb;
+                        changes.push((waves.len(), settings));
+                    }
+                    Event::ChangeChorus(chorus) => {
+                        settings.effects.chorus = *chorus;
+                        changes.push((waves.len(), settings));
+                    }
+                    Event::ChangeFilter(filter) => {
+                        settings.filter = *filter;
+                        changes.push((waves.len(), settings));
+                    }
+                    Event::ChangeFilterEnvelope(envelope) => {
+                        settings.filter_envelope = *envelope;
+                        changes.push((waves.len(), settings));
+                    }
                 },
             }
         }
-        ChannelWave::new(waves)
+        ChannelWave::new(waves, changes)
     }
 
-    pub fn play(&self, player: &Player) {
+    /// 全チャンネルを合成した波形を生成する
+    ///
+    /// 返される [`MusicWave`] は全チャンネルの演奏が終わると終了するため、
+    /// 最後まで読み出すことでファイルなどに書き出すことができる。
+    pub fn to_music_wave(&self) -> MusicWave {
         let channel_waves = (0..16).map(|i| self.generate_channel_wave(i)).collect();
-        let music = MusicWave::new(channel_waves);
-        player.append(music);
+        MusicWave::new(channel_waves)
+    }
+
+    pub fn play(&self, player: &Player) {
+        player.append(self.to_music_wave());
     }
 
     fn fmt_channel(&self, i: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -125,17 +314,19 @@ impl LmmlTimeline {
                         hz,
                         volume,
                         waveform,
+                        ..
                     } => {
                         write!(
                             f,
-                            "Note: {} Hz, {} ms, volume {}, waveform {}",
-                            hz, note.length_ms, volume, waveform
+                            "Note: {} Hz, {} ms (sound {} ms), volume {}, waveform {}",
+                            hz, note.length_ms, note.sound_ms, volume, waveform
                         )?;
                     }
                     NoteType::Chord {
                         ref hzs,
                         volume,
                         waveform,
+                        ..
                     } => {
                         write!(f, "Chord: [")?;
                         for hz in hzs {
@@ -143,8 +334,17 @@ impl LmmlTimeline {
                         }
                         write!(
                             f,
-                            "] Hz, {} ms, volume {}, waveform {}",
-                            note.length_ms, volume, waveform
+                            "] Hz, {} ms (sound {} ms), volume {}, waveform {}",
+                            note.length_ms, note.sound_ms, volume, waveform
+                        )?;
+                    }
+                    NoteType::Drum {
+                        ref drums, volume, ..
+                    } => {
+                        write!(
+                            f,
+                            "Drum: {:?}, {} ms (sound {} ms), volume {}",
+                            drums, note.length_ms, note.sound_ms, volume
                         )?;
                     }
                     NoteType::Rest => {
@@ -155,6 +355,24 @@ impl LmmlTimeline {
                     Event::ChangeTempo(tempo) => {
                         write!(f, "Event ChangeTempo: {}", tempo)?;
                     }
+                    Event::ChangePan(pan) => {
+                        write!(f, "Event ChangePan: {}", pan)?;
+                    }
+                    Event::ChangeDelay(delay) => {
+                        write!(f, "Event ChangeDelay: {:?}", delay)?;
+                    }
+                    Event::ChangeReverb(reverb) => {
+                        write!(f, "Event ChangeReverb: {:?}", reverb)?;
+                    }
+                    Event::ChangeChorus(chorus) => {
+                        write!(f, "Event ChangeChorus: {:?}", chorus)?;
+                    }
+                    Event::ChangeFilter(filter) => {
+                        write!(f, "Event ChangeFilter: {:?}", filter)?;
+                    }
+                    Event::ChangeFilterEnvelope(envelope) => {
+                        write!(f, "Event ChangeFilterEnvelope: {:?}", envelope)?;
+                    }
                 },
             }
             writeln!(f)?;